@@ -8,6 +8,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "proto/clans.proto",
                 "proto/players.proto",
                 "proto/battle.proto",
+                "proto/content.proto",
             ],
             &["proto/"],
         )?;
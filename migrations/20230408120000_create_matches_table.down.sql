@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE matches;
+
+DROP TYPE match_end_reason;
\ No newline at end of file
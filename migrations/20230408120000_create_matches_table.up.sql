@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TYPE match_end_reason as ENUM ('AllAnimalsDead', 'Surrender', 'TurnLimit');
+
+CREATE TABLE matches
+(
+    id SERIAL PRIMARY KEY,
+    player1_id INTEGER NOT NULL REFERENCES players (id) ON DELETE CASCADE,
+    player2_id INTEGER NOT NULL REFERENCES players (id) ON DELETE CASCADE,
+    map_name CHARACTER VARYING(30) NOT NULL,
+    player1_animals INTEGER[] NOT NULL,
+    player2_animals INTEGER[] NOT NULL,
+    winner_id INTEGER NULL,
+    end_reason match_end_reason NOT NULL,
+    player1_glory_delta INTEGER NOT NULL DEFAULT 0,
+    player2_glory_delta INTEGER NOT NULL DEFAULT 0,
+    started_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX matches_player1_idx ON matches (player1_id, finished_at);
+CREATE INDEX matches_player2_idx ON matches (player2_id, finished_at);
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Add down migration script here
+UPDATE matches SET end_reason = 'Surrender' WHERE end_reason = 'Disconnected';
+
+ALTER TYPE match_end_reason RENAME TO match_end_reason_old;
+
+CREATE TYPE match_end_reason as ENUM ('AllAnimalsDead', 'Surrender', 'TurnLimit');
+
+ALTER TABLE matches ALTER COLUMN end_reason TYPE match_end_reason USING end_reason::text::match_end_reason;
+
+DROP TYPE match_end_reason_old;
\ No newline at end of file
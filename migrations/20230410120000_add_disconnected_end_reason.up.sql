@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TYPE match_end_reason ADD VALUE 'Disconnected';
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE replays
+(
+    match_id INTEGER PRIMARY KEY REFERENCES matches (id) ON DELETE CASCADE,
+    replay BYTEA NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,28 @@
+//Checks the game content before it is deployed, every problem is printed with its path
+//Usage: cargo run --bin validate-content [content dir]
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use animal_combat_grpc::{content::GameContent, rules::BattleRules};
+
+fn main() -> ExitCode {
+    let dir = PathBuf::from(std::env::args().nth(1).unwrap_or("data".to_owned()));
+    let rules = BattleRules::load();
+    let content = match GameContent::read(&dir) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let errors = content.errors(&rules);
+    for error in &errors {
+        eprintln!("{error}");
+    }
+    if !errors.is_empty() {
+        eprintln!("{} errors in {}", errors.len(), dir.display());
+        return ExitCode::FAILURE;
+    }
+    println!("Content in {} is valid", dir.display());
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,376 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::rules::BattleRules;
+use crate::services::battle::{
+    battle_command::Command, client_battle_message::Message, AnimalSnapshot, BattleCommand,
+    BattleSnapshot, BattleState, DamageAnimal, EffectType, EndTurn, GameMap, GameObjectType,
+    GetBattleState, MoveAnimal, PickAnimal, PlaceAnimal, PlaceAnimals, Position, Ready, TurnToPick,
+    UseAnimal,
+};
+use crate::{Animals, BattleMessage, ATTACK_AP_COST, MOVE_AP_COST};
+
+//Bots are not stored in the database, so they get negative ids
+pub fn is_bot(player_id: i32) -> bool {
+    player_id < 0
+}
+
+//Decides what the bot does, positions are absolute
+pub trait BotStrategy: Send {
+    fn pick(&mut self, snapshot: &BattleSnapshot, player_id: i32) -> Option<PickAnimal>;
+
+    fn place(&mut self, snapshot: &BattleSnapshot, player_id: i32) -> Option<PlaceAnimals>;
+
+    //Messages for the whole turn, the turn is ended after them
+    fn turn(&mut self, snapshot: &BattleSnapshot, player_id: i32) -> Vec<Message>;
+}
+
+//Plays the battle like a client, asking for the battle state whenever it has to act
+pub async fn run_bot(
+    player_id: i32,
+    mut strategy: Box<dyn BotStrategy>,
+    tx: mpsc::Sender<BattleMessage>,
+    mut rx: mpsc::Receiver<Result<BattleCommand, Status>>,
+) {
+    //The battle waits for the bot to connect, like for any client
+    if tx
+        .send(BattleMessage::Connected { player_id })
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let mut messages = vec![Message::Ready(Ready {})];
+    loop {
+        for message in messages.drain(..) {
+            if tx
+                .send(BattleMessage::from_client(player_id, message))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        let Some(res) = rx.recv().await else {
+            return;
+        };
+        let Ok(BattleCommand {
+            command: Some(command),
+        }) = res
+        else {
+            continue;
+        };
+        match command {
+            Command::TurnToPick(TurnToPick {
+                player_id: turn, ..
+            }) if turn.is_none() || turn == Some(player_id) => {
+                messages.push(Message::GetState(GetBattleState {}));
+            }
+            Command::Snapshot(snapshot) => {
+                let my_turn = snapshot.current_turn == Some(player_id);
+                match snapshot.state() {
+                    BattleState::PickStage if my_turn => {
+                        messages.extend(strategy.pick(&snapshot, player_id).map(Message::Pick));
+                    }
+                    BattleState::PlacementStage => {
+                        messages.extend(strategy.place(&snapshot, player_id).map(Message::Place));
+                    }
+                    BattleState::GameStage if my_turn => {
+                        messages.extend(strategy.turn(&snapshot, player_id));
+                        messages.push(Message::End(EndTurn {}));
+                    }
+                    _ => {}
+                }
+                let map = snapshot.map.clone().unwrap_or_default();
+                messages = messages
+                    .into_iter()
+                    .map(|f| client_view(f, snapshot.invert, &map))
+                    .collect();
+            }
+            Command::Ended(_) => return,
+            _ => {}
+        }
+    }
+}
+
+//Clients send positions from their side of the board.
+//Placement is flipped for the second player, moves and attacks for the first one
+fn client_view(message: Message, invert: bool, map: &GameMap) -> Message {
+    let flip = |position: &mut Option<Position>| {
+        if let Some(position) = position {
+            position.y = map.flip(position.y);
+        }
+    };
+    match message {
+        Message::Place(mut place) if invert => {
+            for animal in &mut place.animals {
+                flip(&mut animal.position);
+            }
+            Message::Place(place)
+        }
+        Message::Move(mut animal) if !invert => {
+            flip(&mut animal.position);
+            Message::Move(animal)
+        }
+        Message::Damage(mut animal) if !invert => {
+            flip(&mut animal.position);
+            Message::Damage(animal)
+        }
+        message => message,
+    }
+}
+
+//Attacks whenever it can deal the most damage, otherwise walks to the closest enemy
+pub struct GreedyBot {
+    animals: Arc<Animals>,
+    rules: BattleRules,
+}
+
+impl GreedyBot {
+    pub(crate) fn new(animals: Arc<Animals>, rules: BattleRules) -> Self {
+        Self { animals, rules }
+    }
+
+    fn damage(&self, attacker: &AnimalSnapshot, target: &AnimalSnapshot) -> i32 {
+        let (Some(attacker), Some(target_animal)) = (
+            self.animals.get(attacker.animal_id),
+            self.animals.get(target.animal_id),
+        ) else {
+            return 0;
+        };
+        let damage = (1f32 - target_animal.resistance / 100f32) * attacker.damage as f32;
+        (damage as i32).min(target.health)
+    }
+}
+
+fn has_effect(animal: &AnimalSnapshot, effect: EffectType) -> bool {
+    animal.effects.iter().any(|f| f.effect == effect as i32)
+}
+
+fn distance(a: &Position, b: &Position) -> i32 {
+    (a.x - b.x).abs() + (a.y - b.y).abs()
+}
+
+//The board of the battle, as the bot sees it
+impl GameMap {
+    fn on_board(&self, x: i32, y: i32) -> bool {
+        (0..self.width).contains(&x) && (0..self.height).contains(&y)
+    }
+
+    fn flip(&self, y: i32) -> i32 {
+        self.height - 1 - y
+    }
+
+    fn zone(&self, player2: bool) -> Range<i32> {
+        let zone = if player2 {
+            &self.player2_zone
+        } else {
+            &self.player1_zone
+        };
+        zone.as_ref().map_or(0..0, |f| f.start..f.end)
+    }
+}
+
+//Squares taken by map objects, eggs and animals
+fn occupied(snapshot: &BattleSnapshot) -> Vec<(i32, i32)> {
+    let mut occupied: Vec<(i32, i32)> = snapshot
+        .map
+        .iter()
+        .flat_map(|f| f.objects.iter())
+        .filter(|f| f.object_type() != GameObjectType::Walkable)
+        .chain(snapshot.objects.iter())
+        .map(|f| (f.x, f.y))
+        .collect();
+    occupied.extend(
+        snapshot
+            .animals
+            .iter()
+            .filter_map(|f| f.position.as_ref())
+            .map(|f| (f.x, f.y)),
+    );
+    occupied
+}
+
+//Squares the animal can move to in a straight line, with the number of steps
+fn reachable(
+    from: &Position,
+    steps: i32,
+    occupied: &[(i32, i32)],
+    map: &GameMap,
+) -> Vec<(Position, i32)> {
+    let mut squares = vec![(from.clone(), 0)];
+    for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+        for step in 1..=steps {
+            let (x, y) = (from.x + dx * step, from.y + dy * step);
+            if !map.on_board(x, y) || occupied.contains(&(x, y)) {
+                break;
+            }
+            squares.push((Position { x, y }, step));
+        }
+    }
+    squares
+}
+
+impl BotStrategy for GreedyBot {
+    fn pick(&mut self, snapshot: &BattleSnapshot, _player_id: i32) -> Option<PickAnimal> {
+        self.animals
+            .animals
+            .iter()
+            .filter(|f| snapshot.animals.iter().all(|g| g.animal_id != f.id))
+            .max_by_key(|f| f.hp * f.damage)
+            .map(|f| PickAnimal { animal_id: f.id })
+    }
+
+    fn place(&mut self, snapshot: &BattleSnapshot, player_id: i32) -> Option<PlaceAnimals> {
+        let animals: Vec<i32> = snapshot
+            .animals
+            .iter()
+            .filter(|f| f.player_id == player_id && f.position.is_none())
+            .map(|f| f.animal_id)
+            .collect();
+        if animals.len() != self.rules.team_size {
+            return None;
+        }
+        //The rows closest to the opponent, in the middle of the board
+        let map = snapshot.map.as_ref()?;
+        let rows = map.zone(snapshot.invert);
+        let front = if snapshot.invert {
+            rows.start
+        } else {
+            rows.end - 1
+        };
+        let middle = map.width / 2;
+        let occupied = occupied(snapshot);
+        let mut squares: Vec<Position> = (0..map.width)
+            .flat_map(|x| rows.clone().map(move |y| Position { x, y }))
+            .filter(|f| !occupied.contains(&(f.x, f.y)))
+            .collect();
+        squares.sort_by_key(|f| ((f.y - front).abs(), (f.x - middle).abs()));
+        Some(PlaceAnimals {
+            animals: animals
+                .into_iter()
+                .zip(squares)
+                .map(|(animal_id, position)| PlaceAnimal {
+                    animal_id,
+                    position: Some(position),
+                })
+                .collect(),
+        })
+    }
+
+    fn turn(&mut self, snapshot: &BattleSnapshot, player_id: i32) -> Vec<Message> {
+        let alive = |f: &&AnimalSnapshot| f.position.is_some() && f.health > 0;
+        let enemies: Vec<&AnimalSnapshot> = snapshot
+            .animals
+            .iter()
+            .filter(alive)
+            .filter(|f| f.player_id != player_id)
+            .collect();
+        let map = snapshot.map.clone().unwrap_or_default();
+        let occupied = occupied(snapshot);
+
+        //(score, animal, square, steps, target)
+        let mut attack: Option<(i32, &AnimalSnapshot, Position, i32, &AnimalSnapshot)> = None;
+        //(path length, animal, square)
+        let mut approach: Option<(i32, &AnimalSnapshot, Position)> = None;
+        for animal in snapshot
+            .animals
+            .iter()
+            .filter(alive)
+            .filter(|f| f.player_id == player_id && !has_effect(f, EffectType::Stun))
+        {
+            let position = animal.position.as_ref().unwrap();
+            let ap = animal.action_points.unwrap_or_default();
+            let steps = animal
+                .mobility
+                .unwrap_or_default()
+                .min((ap / MOVE_AP_COST) as i32);
+            let paths = path_lengths(position, &enemies, &occupied, &map);
+            let path_length = |square: &Position| {
+                paths
+                    .get(&(square.x, square.y))
+                    .copied()
+                    .unwrap_or(i32::MAX)
+            };
+            for (square, steps) in reachable(position, steps, &occupied, &map) {
+                let can_attack = ap - steps as f32 * MOVE_AP_COST >= ATTACK_AP_COST
+                    && !has_effect(animal, EffectType::Pacified);
+                for enemy in &enemies {
+                    if !can_attack || distance(&square, enemy.position.as_ref().unwrap()) != 1 {
+                        continue;
+                    }
+                    let damage = self.damage(animal, enemy);
+                    //Finishing an enemy is always worth it, otherwise weaker enemies go first
+                    let score = if damage >= enemy.health {
+                        1000
+                    } else {
+                        damage * 2 - enemy.health / 10
+                    } - steps;
+                    if attack.as_ref().map_or(true, |f| score > f.0) {
+                        attack = Some((score, animal, square.clone(), steps, enemy));
+                    }
+                }
+                let length = path_length(&square);
+                if length < path_length(position)
+                    && approach.as_ref().map_or(true, |f| length < f.0)
+                {
+                    approach = Some((length, animal, square));
+                }
+            }
+        }
+
+        let mut messages = Vec::new();
+        if let Some((_, animal, square, steps, target)) = attack {
+            messages.push(Message::Use(UseAnimal {
+                animal_id: animal.animal_id,
+            }));
+            if steps > 0 {
+                messages.push(Message::Move(MoveAnimal {
+                    position: Some(square),
+                }));
+            }
+            messages.push(Message::Damage(DamageAnimal {
+                position: target.position.clone(),
+            }));
+        } else if let Some((_, animal, square)) = approach {
+            messages.push(Message::Use(UseAnimal {
+                animal_id: animal.animal_id,
+            }));
+            messages.push(Message::Move(MoveAnimal {
+                position: Some(square),
+            }));
+        }
+        messages
+    }
+}
+
+//Number of squares from every free square to the closest enemy, going around obstacles
+fn path_lengths(
+    from: &Position,
+    enemies: &[&AnimalSnapshot],
+    occupied: &[(i32, i32)],
+    map: &GameMap,
+) -> HashMap<(i32, i32), i32> {
+    let mut lengths = HashMap::new();
+    let mut queue = VecDeque::new();
+    for enemy in enemies.iter().filter_map(|f| f.position.as_ref()) {
+        lengths.insert((enemy.x, enemy.y), 0);
+        queue.push_back((enemy.x, enemy.y));
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        let length = lengths[&(x, y)] + 1;
+        for (x, y) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+            //The animal itself does not block its own way
+            let free = (x, y) == (from.x, from.y) || !occupied.contains(&(x, y));
+            if map.on_board(x, y) && free && !lengths.contains_key(&(x, y)) {
+                lengths.insert((x, y), length);
+                queue.push_back((x, y));
+            }
+        }
+    }
+    lengths
+}
@@ -0,0 +1,354 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use tokio::time;
+use tracing::{error, info};
+
+use crate::rules::BattleRules;
+use crate::{AbilityType, Animals, Maps};
+
+const ANIMALS_FILE: &str = "animals.json";
+const MAPS_FILE: &str = "maps.json";
+const CONTENT_POLL_TIME: u64 = 5;
+
+//Animals and maps of one version of the game data
+#[derive(Clone)]
+pub struct GameContent {
+    pub animals: Arc<Animals>,
+    pub maps: Arc<Maps>,
+    //Hash of the content, clients fetch it again when it changes
+    pub version: String,
+}
+
+impl GameContent {
+    //The data, the server was built with
+    pub fn bundled() -> Self {
+        Self::new(Animals::load(), Maps::load())
+    }
+
+    fn new(animals: Animals, maps: Maps) -> Self {
+        let mut bytes = serde_json::to_vec(&animals).unwrap();
+        bytes.extend(serde_json::to_vec(&maps).unwrap());
+        Self {
+            animals: Arc::new(animals),
+            maps: Arc::new(maps),
+            version: format!("{:016x}", fnv1a(&bytes)),
+        }
+    }
+
+    //Content, which is valid for the rules
+    pub fn from_dir(dir: &Path, rules: &BattleRules) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = Self::read(dir)?;
+        content.validate(rules)?;
+        Ok(content)
+    }
+
+    //Content as it is in the files, without checking it
+    pub fn read(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::new(
+            read_file(&dir.join(ANIMALS_FILE))?,
+            read_file(&dir.join(MAPS_FILE))?,
+        ))
+    }
+
+    pub fn validate(&self, rules: &BattleRules) -> Result<(), String> {
+        let errors = self.errors(rules);
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    //Everything, that would break a battle
+    pub fn errors(&self, rules: &BattleRules) -> Vec<ContentError> {
+        let mut errors = Vec::new();
+        self.animal_errors(rules, &mut errors);
+        self.map_errors(rules, &mut errors);
+        errors
+    }
+
+    fn animal_errors(&self, rules: &BattleRules, errors: &mut Vec<ContentError>) {
+        let animals = &self.animals.animals;
+        //Both players pick different animals
+        if animals.len() < rules.pick_count() {
+            errors.push(ContentError::new(
+                ANIMALS_FILE,
+                format!("{} animals, {} needed", animals.len(), rules.pick_count()),
+            ));
+        }
+        for (i, animal) in animals.iter().enumerate() {
+            let path = format!("{ANIMALS_FILE}: animals[{i}]");
+            if let Some(j) = animals[..i].iter().position(|f| f.id == animal.id) {
+                errors.push(ContentError::new(
+                    format!("{path}.id"),
+                    format!("Id {} is used by animals[{j}] too", animal.id),
+                ));
+            }
+            if animal.hp <= 0 {
+                errors.push(ContentError::new(
+                    format!("{path}.hp"),
+                    "Must be positive".to_string(),
+                ));
+            }
+            for (k, ability) in animal.abilities.iter().enumerate() {
+                let path = format!("{path}.abilities[{k}]");
+                if let Some(l) = animal.abilities[..k]
+                    .iter()
+                    .position(|f| f.name == ability.name)
+                {
+                    errors.push(ContentError::new(
+                        format!("{path}.name"),
+                        format!("\"{}\" is used by abilities[{l}] too", ability.name),
+                    ));
+                }
+                if !matches!(ability.ability_type, AbilityType::Active) {
+                    continue;
+                }
+                for (field, value) in [("cost", ability.cost), ("cooldown", ability.cooldown)] {
+                    match value {
+                        None => errors.push(ContentError::new(
+                            format!("{path}.{field}"),
+                            "Missing for an active ability".to_string(),
+                        )),
+                        Some(value) if value < 0 => errors.push(ContentError::new(
+                            format!("{path}.{field}"),
+                            "Must not be negative".to_string(),
+                        )),
+                        Some(_) => {}
+                    }
+                }
+                if ability.target.is_none() {
+                    errors.push(ContentError::new(
+                        format!("{path}.target"),
+                        "Missing for an active ability".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn map_errors(&self, rules: &BattleRules, errors: &mut Vec<ContentError>) {
+        let maps = &self.maps.maps;
+        if maps.iter().all(|f| f.weight == 0) {
+            errors.push(ContentError::new(
+                MAPS_FILE,
+                "No maps in the rotation".to_string(),
+            ));
+        }
+        for (i, map) in maps.iter().enumerate() {
+            let path = format!("{MAPS_FILE}: maps[{i}]");
+            if let Some(j) = maps[..i].iter().position(|f| f.map_name == map.map_name) {
+                errors.push(ContentError::new(
+                    format!("{path}.map_name"),
+                    format!("\"{}\" is used by maps[{j}] too", map.map_name),
+                ));
+            }
+            if map.width <= 0 || map.height <= 0 {
+                errors.push(ContentError::new(
+                    path.clone(),
+                    format!("{}x{} board is empty", map.width, map.height),
+                ));
+            }
+            for (field, zone) in [
+                ("player1_zone", &map.player1_zone),
+                ("player2_zone", &map.player2_zone),
+            ] {
+                if zone.is_empty() || zone.start < 0 || zone.end > map.height {
+                    errors.push(ContentError::new(
+                        format!("{path}.{field}"),
+                        format!(
+                            "Rows {zone:?} are not on the {} rows of the board",
+                            map.height
+                        ),
+                    ));
+                }
+            }
+            if map.player1_zone.start < map.player2_zone.end
+                && map.player2_zone.start < map.player1_zone.end
+            {
+                errors.push(ContentError::new(
+                    format!("{path}.player2_zone"),
+                    "Overlaps player1_zone".to_string(),
+                ));
+            }
+            for (k, object) in map.objects.iter().enumerate() {
+                let path = format!("{path}.objects[{k}]");
+                if !map.on_board(object.x, object.y) {
+                    errors.push(ContentError::new(
+                        path.clone(),
+                        format!(
+                            "({}, {}) is outside the {}x{} board",
+                            object.x, object.y, map.width, map.height
+                        ),
+                    ));
+                }
+                if let Some(l) = map.objects[..k]
+                    .iter()
+                    .position(|f| f.x == object.x && f.y == object.y)
+                {
+                    errors.push(ContentError::new(
+                        path,
+                        format!("({}, {}) overlaps objects[{l}]", object.x, object.y),
+                    ));
+                }
+            }
+            //Animals of a player, who did not place them, go to random free squares
+            for (player, player2) in [(1, false), (2, true)] {
+                let free = (0..map.width)
+                    .flat_map(|x| map.zone(player2).map(move |y| (x, y)))
+                    .filter(|(x, y)| !map.obstacles().any(|f| f.x == *x && f.y == *y))
+                    .count();
+                if free < rules.team_size {
+                    errors.push(ContentError::new(
+                        path.clone(),
+                        format!(
+                            "{free} free squares in the zone of player {player}, {} needed",
+                            rules.team_size
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+//Problem in the content and where it is
+#[derive(Debug)]
+pub struct ContentError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ContentError {
+    fn new(path: impl Into<String>, message: String) -> Self {
+        Self {
+            path: path.into(),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for ContentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+//Content for new matches. A reload never touches running battles,
+//they keep the snapshot they were created with
+#[derive(Clone)]
+pub struct ContentStore {
+    dir: Option<PathBuf>,
+    rules: BattleRules,
+    content: Arc<RwLock<GameContent>>,
+}
+
+impl ContentStore {
+    //Content from the directory in CONTENT_DIR, or the bundled one.
+    //Fails, if it is not valid for the battle rules
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let rules = BattleRules::load();
+        match std::env::var("CONTENT_DIR") {
+            Ok(dir) => Self::from_dir(dir, rules),
+            Err(_) => {
+                let store = Self {
+                    rules,
+                    ..Self::default()
+                };
+                store
+                    .current()
+                    .validate(&store.rules)
+                    .map_err(|e| format!("Bundled content is invalid:\n{e}"))?;
+                Ok(store)
+            }
+        }
+    }
+
+    pub fn from_dir(
+        dir: impl Into<PathBuf>,
+        rules: BattleRules,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = dir.into();
+        let content = GameContent::from_dir(&dir, &rules)
+            .map_err(|e| format!("Failed to load content from {}:\n{e}", dir.display()))?;
+        Ok(Self {
+            dir: Some(dir),
+            rules,
+            content: Arc::new(RwLock::new(content)),
+        })
+    }
+
+    pub fn current(&self) -> GameContent {
+        self.content.read().unwrap().clone()
+    }
+
+    //Invalid content is not applied, the old one stays
+    pub fn reload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let content = GameContent::from_dir(dir, &self.rules)?;
+        *self.content.write().unwrap() = content;
+        Ok(())
+    }
+
+    //Reloads the content every time its files are changed
+    pub async fn watch(self) {
+        let Some(dir) = self.dir.clone() else {
+            return;
+        };
+        let mut modified = modified_at(&dir);
+        let mut interval = time::interval(Duration::from_secs(CONTENT_POLL_TIME));
+        loop {
+            interval.tick().await;
+            let now = modified_at(&dir);
+            if now == modified {
+                continue;
+            }
+            modified = now;
+            match self.reload() {
+                Ok(()) => info!("Content reloaded from {}", dir.display()),
+                Err(e) => error!("Failed to reload content from {}: {e}", dir.display()),
+            }
+        }
+    }
+}
+
+impl Default for ContentStore {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            rules: BattleRules::load(),
+            content: Arc::new(RwLock::new(GameContent::bundled())),
+        }
+    }
+}
+
+fn modified_at(dir: &Path) -> Vec<Option<SystemTime>> {
+    [ANIMALS_FILE, MAPS_FILE]
+        .into_iter()
+        .map(|f| {
+            std::fs::metadata(dir.join(f))
+                .and_then(|f| f.modified())
+                .ok()
+        })
+        .collect()
+}
+
+//Stays the same between builds, unlike the hasher of std
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, f| {
+        (hash ^ *f as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,32 +1,52 @@
 #![allow(clippy::type_complexity)]
 
+pub mod bot;
+pub mod content;
+pub mod matchmaking;
+pub mod rules;
 pub mod services;
 
+use crate::bot::GreedyBot;
+use crate::content::ContentStore;
 use crate::services::battle::battle_command::Command;
+use crate::services::battle::client_battle_message;
 use crate::services::battle::{
-    AnimalDamaged, AnimalDead, AnimalMoved, AnimalPicked, AnimalPlaced, AnimalsPlaced, BattleState,
-    DamageAnimal, GameMap, GameObject, GameObjectType, MoveAnimal, PickAnimal, PlaceAnimal,
-    PlaceAnimals, SetBattleState, TurnToPick, UseAnimal,
+    AbilityUsed, ActionPointsUpdated, AnimalDamaged, AnimalDead, AnimalHealed, AnimalMoved,
+    AnimalPicked, AnimalPlaced, AnimalSnapshot, AnimalsPlaced, BattleCommand, BattleSnapshot,
+    BattleState, DamageAnimal, EffectApplied, EffectExpired, EffectType, GameMap, GameObject,
+    GameObjectType, MatchEndReason, MatchEnded, MoveAnimal, ObjectPlaced, ObjectRemoved,
+    PickAnimal, PlaceAnimal, PlaceAnimals, QueueStatus, Replay, ReplayCommand, SetBattleState,
+    TurnToPick, UseAbility, UseAnimal, Zone,
 };
 use bevy_ecs::prelude::*;
 use bevy_ecs::schedule::ExecutorKind;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use jsonwebtoken::{DecodingKey, Validation};
+use matchmaking::{Candidate, Clock, MatchmakingStrategy, StickoStrategy, SystemClock};
+use prost::Message;
 use prost_types::Timestamp;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::Rng;
+use rand::{Rng, RngCore, SeedableRng};
+use rules::BattleRules;
 use serde::{Deserialize, Serialize};
 use services::battle;
-use skillratings::sticko::StickoRating;
+use services::content::{AbilityInfo, AnimalInfo};
+use skillratings::sticko::{sticko, StickoConfig, StickoRating};
+use skillratings::Outcomes;
+use sqlx::{Pool, Postgres, Transaction};
 use std::collections::HashSet;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
 use std::{collections::HashMap, time::Duration};
 use tokio::{
-    sync::{broadcast::Sender, mpsc::Receiver},
-    time,
+    sync::mpsc::{self, error::TrySendError, Receiver},
+    sync::oneshot,
+    time::{self, Instant},
 };
 use tonic::{Request, Status};
+use tracing::{error, warn};
 
 //Put this in any service, except Auth
 pub fn jwt_interceptor(mut req: Request<()>) -> Result<Request<()>, Status> {
@@ -57,6 +77,19 @@ impl From<Map> for GameMap {
         Self {
             name: value.map_name,
             objects: value.objects.into_iter().map(|f| f.into()).collect(),
+            width: value.width,
+            height: value.height,
+            player1_zone: Some(value.player1_zone.into()),
+            player2_zone: Some(value.player2_zone.into()),
+        }
+    }
+}
+
+impl From<Range<i32>> for Zone {
+    fn from(value: Range<i32>) -> Self {
+        Self {
+            start: value.start,
+            end: value.end,
         }
     }
 }
@@ -72,6 +105,80 @@ impl From<MapObject> for GameObject {
     }
 }
 
+impl From<&Animal> for AnimalInfo {
+    fn from(value: &Animal) -> Self {
+        Self {
+            id: value.id,
+            name: value.name.clone(),
+            hp: value.hp,
+            damage: value.damage,
+            resistance: value.resistance,
+            mobility: value.mobility,
+            png_name: value.png_name.clone(),
+            description: value.description.clone(),
+            action_points: value.action_points,
+            action_points_per_turn: value.action_points_per_turn,
+            abilities: value.abilities.iter().map(|f| f.into()).collect(),
+        }
+    }
+}
+
+impl From<&Ability> for AbilityInfo {
+    fn from(value: &Ability) -> Self {
+        Self {
+            name: value.name.clone(),
+            icon_name: value.icon_name.clone(),
+            description: value.description.clone(),
+            r#type: Into::<services::content::AbilityType>::into(&value.ability_type).into(),
+            cooldown: value.cooldown,
+            cost: value.cost,
+            target: value
+                .target
+                .as_ref()
+                .map(|f| Into::<services::content::AbilityTarget>::into(f).into()),
+        }
+    }
+}
+
+impl From<&AbilityType> for services::content::AbilityType {
+    fn from(value: &AbilityType) -> Self {
+        match value {
+            AbilityType::Active => Self::Active,
+            AbilityType::Passive => Self::Passive,
+        }
+    }
+}
+
+impl From<&AbilityTarget> for services::content::AbilityTarget {
+    fn from(value: &AbilityTarget) -> Self {
+        match value {
+            AbilityTarget::NoTarget => Self::NoTarget,
+            AbilityTarget::Enemy => Self::Enemy,
+            AbilityTarget::EmptySquare => Self::EmptySquare,
+        }
+    }
+}
+
+#[derive(sqlx::Type)]
+#[sqlx(type_name = "match_end_reason")]
+enum SqlMatchEndReason {
+    AllAnimalsDead,
+    Surrender,
+    TurnLimit,
+    Disconnected,
+}
+
+impl From<MatchEndReason> for SqlMatchEndReason {
+    fn from(value: MatchEndReason) -> Self {
+        match value {
+            MatchEndReason::AllAnimalsDead => Self::AllAnimalsDead,
+            MatchEndReason::Surrender => Self::Surrender,
+            MatchEndReason::TurnLimit => Self::TurnLimit,
+            MatchEndReason::Disconnected => Self::Disconnected,
+        }
+    }
+}
+
 impl From<ObjectType> for GameObjectType {
     fn from(value: ObjectType) -> Self {
         match value {
@@ -89,6 +196,26 @@ pub struct Match {
     player2: i32,
     player2_ready: bool,
     map: Map,
+    //Content the match was made with, reloads do not change it
+    animals: Arc<Animals>,
+    //Glory is not changed after unranked battles
+    ranked: bool,
+    //Gets the result, when the battle ends
+    result_tx: Option<mpsc::Sender<MatchEnded>>,
+}
+
+impl Match {
+    pub fn players(&self) -> (i32, i32) {
+        (self.player1, self.player2)
+    }
+
+    pub fn is_ranked(&self) -> bool {
+        self.ranked
+    }
+
+    pub fn map_name(&self) -> &str {
+        &self.map.map_name
+    }
 }
 
 pub struct Player {
@@ -96,95 +223,420 @@ pub struct Player {
     join_time: DateTime<Utc>,
 }
 
+//Match waiting for both players to accept it
+struct PendingMatch {
+    m: Match,
+    players: Vec<(i32, Player)>,
+    accepted: Vec<i32>,
+    deadline: DateTime<Utc>,
+}
+
 pub struct Matchmaker {
     players: HashMap<i32, Player>,
+    pending: Vec<PendingMatch>,
+    //Players who declined a match, and until when they are not matched
+    penalties: HashMap<i32, DateTime<Utc>>,
+    //The last opponent of every player, and when they were matched
+    last_opponents: HashMap<i32, (i32, DateTime<Utc>)>,
+    //The map of the last match of every player
+    last_maps: HashMap<i32, String>,
+    content: ContentStore,
+    active: ActivePlayers,
+    strategy: Box<dyn MatchmakingStrategy>,
+    clock: Box<dyn Clock>,
+    rng: Box<dyn RngCore + Send>,
+    //Players are matched with a bot after waiting this long
+    bot_wait: Option<chrono::Duration>,
+    next_bot_id: i32,
 }
 
 impl Matchmaker {
-    fn new() -> Self {
+    pub fn new(
+        strategy: Box<dyn MatchmakingStrategy>,
+        clock: Box<dyn Clock>,
+        rng: Box<dyn RngCore + Send>,
+    ) -> Self {
         Self {
             players: HashMap::new(),
+            pending: Vec::new(),
+            penalties: HashMap::new(),
+            last_opponents: HashMap::new(),
+            last_maps: HashMap::new(),
+            content: ContentStore::default(),
+            active: ActivePlayers::default(),
+            strategy,
+            clock,
+            rng,
+            bot_wait: None,
+            next_bot_id: -1,
         }
     }
 
-    fn add_player(&mut self, id: i32, rating: StickoRating) {
+    pub fn with_bot_wait(mut self, bot_wait: Option<chrono::Duration>) -> Self {
+        self.bot_wait = bot_wait;
+        self
+    }
+
+    pub fn with_content(mut self, content: ContentStore) -> Self {
+        self.content = content;
+        self
+    }
+
+    pub fn with_active_players(mut self, active: ActivePlayers) -> Self {
+        self.active = active;
+        self
+    }
+
+    //Players, who are about to battle or battling, are not queued again
+    pub fn add_player(&mut self, id: i32, rating: StickoRating) {
+        if self.is_pending(id) || self.active.contains(id) {
+            return;
+        }
         self.players.insert(
             id,
             Player {
                 rating,
-                join_time: Utc::now(),
+                join_time: self.clock.now(),
             },
         );
     }
 
-    fn remove_player(&mut self, id: i32) {
+    pub fn remove_player(&mut self, id: i32) {
         self.players.remove(&id);
     }
 
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    //Takes both players out of the queue until they accept the match
+    fn propose_match(&mut self, m: Match) -> DateTime<Utc> {
+        let deadline = self.clock.now() + chrono::Duration::seconds(ACCEPT_TIME as i64);
+        let players = [m.player1, m.player2]
+            .into_iter()
+            .filter_map(|id| self.players.remove(&id).map(|f| (id, f)))
+            .collect();
+        self.pending.push(PendingMatch {
+            m,
+            players,
+            accepted: Vec::new(),
+            deadline,
+        });
+        deadline
+    }
+
+    //Returns the match, when both players accepted it
+    pub fn accept_match(&mut self, id: i32) -> Option<Match> {
+        let index = self
+            .pending
+            .iter()
+            .position(|f| f.m.player1 == id || f.m.player2 == id)?;
+        let pending = &mut self.pending[index];
+        if !pending.accepted.contains(&id) {
+            pending.accepted.push(id);
+        }
+        if pending.accepted.len() < 2 {
+            return None;
+        }
+        let m = self.pending.remove(index).m;
+        self.active.insert(&[m.player1, m.player2]);
+        Some(m)
+    }
+
+    fn is_pending(&self, id: i32) -> bool {
+        self.pending
+            .iter()
+            .any(|f| f.m.player1 == id || f.m.player2 == id)
+    }
+
+    //Match agreed by the players themselves, they leave the queue.
+    //Fails if one of the players is battling or about to battle someone else
+    fn direct_match(&mut self, player1: i32, player2: i32, ranked: bool) -> Result<Match, Status> {
+        if self.is_pending(player1) || self.is_pending(player2) {
+            return Err(Status::failed_precondition(
+                "Player is about to start another battle",
+            ));
+        }
+        if self.active.contains(player1) || self.active.contains(player2) {
+            return Err(Status::failed_precondition("Player is in another battle"));
+        }
+        self.remove_player(player1);
+        self.remove_player(player2);
+        self.active.insert(&[player1, player2]);
+        Ok(self.new_match(player1, player2, ranked))
+    }
+
+    //Match on a map of the current content
+    fn new_match(&mut self, player1: i32, player2: i32, ranked: bool) -> Match {
+        let content = self.content.current();
+        let map = self.choose_map(&content.maps, [player1, player2]);
+        for id in [player1, player2] {
+            self.last_maps.insert(id, map.map_name.clone());
+        }
+        Match {
+            player1,
+            player2,
+            player1_ready: false,
+            player2_ready: false,
+            map,
+            animals: content.animals,
+            ranked,
+            result_tx: None,
+        }
+    }
+
+    //Random map by weight. Maps, the players have just played, are chosen
+    //only when there is nothing else in the rotation
+    fn choose_map(&mut self, maps: &Maps, players: [i32; 2]) -> Map {
+        let rotation: Vec<&Map> = maps.maps.iter().filter(|f| f.weight > 0).collect();
+        let fresh: Vec<&Map> = rotation
+            .iter()
+            .copied()
+            .filter(|f| {
+                players
+                    .iter()
+                    .all(|id| self.last_maps.get(id) != Some(&f.map_name))
+            })
+            .collect();
+        let candidates = if fresh.is_empty() { rotation } else { fresh };
+        let map = candidates
+            .choose_weighted(self.rng.as_mut(), |f| f.weight)
+            .unwrap();
+        (*map).clone()
+    }
+
+    fn decline_match(&mut self, id: i32) -> Option<PendingMatch> {
+        let index = self
+            .pending
+            .iter()
+            .position(|f| f.m.player1 == id || f.m.player2 == id)?;
+        Some(self.pending.remove(index))
+    }
+
+    fn expired_matches(&mut self) -> Vec<PendingMatch> {
+        let now = self.clock.now();
+        let (expired, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|f| f.deadline <= now);
+        self.pending = pending;
+        expired
+    }
+
+    //Players who accepted go back to the front of the queue, others get a penalty.
+    //Returns every player and whether they were put back to the queue
+    fn cancel_match(&mut self, pending: PendingMatch) -> Vec<(i32, bool)> {
+        let front = self.players.values().map(|f| f.join_time).min();
+        let mut cancelled = Vec::new();
+        for (id, mut player) in pending.players {
+            let requeued = pending.accepted.contains(&id);
+            if requeued {
+                player.join_time = front.map_or(player.join_time, |f| f.min(player.join_time));
+                self.players.insert(id, player);
+            } else {
+                self.penalties.insert(
+                    id,
+                    self.clock.now() + chrono::Duration::seconds(DECLINE_PENALTY_TIME as i64),
+                );
+            }
+            cancelled.push((id, requeued));
+        }
+        cancelled
+    }
+
+    fn is_penalized(&self, id: i32) -> bool {
+        self.penalties
+            .get(&id)
+            .is_some_and(|f| *f > self.clock.now())
+    }
+
+    fn clear_penalties(&mut self) {
+        let now = self.clock.now();
+        self.penalties.retain(|_, f| *f > now);
+    }
+
+    //Players in the order they joined
     fn get_all_ids(&self) -> Vec<i32> {
         let mut vec = self
             .players
             .iter()
-            .map(|f| (*f.0, f.1.join_time.timestamp()))
-            .collect::<Vec<(i32, i64)>>();
-        vec.sort_by(|a, b| a.1.cmp(&b.1));
+            .map(|f| (*f.0, f.1.join_time))
+            .collect::<Vec<(i32, DateTime<Utc>)>>();
+        vec.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
         vec.into_iter().map(|f| f.0).collect()
     }
 
-    fn find_match(&mut self, player_id: i32, maps: &Maps) -> Option<Match> {
-        let player = match self.players.get(&player_id) {
-            Some(p) => p,
-            None => return None,
-        };
-        // Calculate the maximum rating difference based on the waiting time
-        let elapsed_time = (Utc::now() - player.join_time)
-            .to_std()
-            .unwrap_or(Duration::default());
-        let max_rating_diff = ((elapsed_time.as_secs_f32() / 6f32 + 1f32) * 100f32) as i32;
-        let max_rating_diff = std::cmp::min(max_rating_diff, 500);
+    fn candidate(&self, id: i32, now: DateTime<Utc>) -> Candidate {
+        let player = &self.players[&id];
+        Candidate {
+            id,
+            rating: player.rating,
+            waited: now - player.join_time,
+            last_opponent: self
+                .last_opponents
+                .get(&id)
+                .map(|(opponent, time)| (*opponent, now - *time)),
+        }
+    }
+
+    //Players with their place in the queue
+    fn queue_status(&self) -> Vec<(i32, QueueStatus)> {
+        let now = self.clock.now();
+        self.get_all_ids()
+            .into_iter()
+            .enumerate()
+            .map(|(position, id)| {
+                let candidate = self.candidate(id, now);
+                (
+                    id,
+                    QueueStatus {
+                        position: position as i32 + 1,
+                        search_range: self.strategy.search_range(&candidate),
+                        waited_seconds: candidate.waited.num_seconds() as i32,
+                        penalty_until: self.penalties.get(&id).filter(|f| **f > now).map(|f| {
+                            Timestamp {
+                                seconds: f.timestamp(),
+                                nanos: 0,
+                            }
+                        }),
+                    },
+                )
+            })
+            .collect()
+    }
 
-        // Find a player with a matching rating and within the allowed rating difference
-        let mut rng = rand::thread_rng();
+    //Finds opponents for the whole queue, the ones who wait longer choose first.
+    //Matched players wait for each other to accept until the returned deadline
+    pub fn find_matches(&mut self) -> Vec<(Match, DateTime<Utc>)> {
+        let now = self.clock.now();
+        let mut candidates = self
+            .get_all_ids()
+            .into_iter()
+            .filter(|id| !self.is_penalized(*id) && !self.active.contains(*id))
+            .map(|id| self.candidate(id, now))
+            .collect::<Vec<Candidate>>();
         let mut matches = Vec::new();
-        for (&id, other_player) in &self.players {
-            if id == player_id {
+        let mut index = 0;
+        while index < candidates.len() {
+            let opponent = self
+                .strategy
+                .find_opponent(&candidates[index], &candidates, self.rng.as_mut())
+                .filter(|id| candidates.iter().any(|f| f.id == *id));
+            let Some(opponent) = opponent else {
+                index += 1;
                 continue;
-            }
-            let rating_diff = (player.rating.rating - other_player.rating.rating).abs() as i32;
-            if rating_diff <= max_rating_diff {
-                matches.push(Match {
-                    player1: player_id,
-                    player2: id,
-                    player1_ready: false,
-                    player2_ready: false,
-                    map: maps.maps.choose(&mut rand::thread_rng()).unwrap().clone(),
-                });
-            }
+            };
+            let player_id = candidates[index].id;
+            candidates.retain(|f| f.id != player_id && f.id != opponent);
+            self.last_opponents.insert(player_id, (opponent, now));
+            self.last_opponents.insert(opponent, (player_id, now));
+            let m = self.new_match(player_id, opponent, true);
+            let deadline = self.propose_match(m.clone());
+            matches.push((m, deadline));
         }
-
-        // If there are no matches, return None
-        if matches.is_empty() {
-            return None;
+        //Nobody was found for too long, so a bot takes the place of the opponent
+        let bot_wait = self.bot_wait;
+        for candidate in candidates
+            .into_iter()
+            .filter(|f| bot_wait.is_some_and(|wait| f.waited >= wait))
+        {
+            let bot_id = self.next_bot_id;
+            self.next_bot_id -= 1;
+            let m = self.new_match(candidate.id, bot_id, false);
+            let deadline = self.propose_match(m.clone());
+            //Bots are always ready to play
+            self.accept_match(bot_id);
+            matches.push((m, deadline));
         }
-
-        // Randomly select a match from the list of possible matches
-        let match_index = rng.gen_range(0..matches.len());
-        let matched_players = matches[match_index].clone();
-        Some(matched_players)
+        matches
     }
 }
 
 impl Default for Matchmaker {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            Box::<StickoStrategy>::default(),
+            Box::new(SystemClock),
+            Box::new(StdRng::from_entropy()),
+        )
     }
 }
 
-#[derive(Clone)]
+//Challenge waiting for the answer of the challenged player
+struct Challenge {
+    challenger_id: i32,
+    player_id: i32,
+    ranked: bool,
+    deadline: DateTime<Utc>,
+}
+
+type Reply = oneshot::Sender<Result<(), Status>>;
+
 pub enum MatchmakerMessage {
-    JoinMatchmaking { id: i32, rating: StickoRating },
-    LeaveMatchmaking { id: i32 },
-    MatchFound(Match),
+    JoinMatchmaking {
+        id: i32,
+        rating: StickoRating,
+    },
+    LeaveMatchmaking {
+        id: i32,
+    },
+    //Sent by the FindMatch stream, the latest stream of the player gets the updates
+    Subscribe {
+        id: i32,
+        tx: mpsc::Sender<MatchmakerUpdate>,
+    },
+    AcceptMatch {
+        id: i32,
+    },
+    DeclineMatch {
+        id: i32,
+    },
+    ChallengePlayer {
+        id: i32,
+        player_id: i32,
+        ranked: bool,
+        tx: Reply,
+    },
+    AcceptChallenge {
+        id: i32,
+        challenger_id: i32,
+        tx: Reply,
+    },
+    DeclineChallenge {
+        id: i32,
+        challenger_id: i32,
+        tx: Reply,
+    },
+    //Unranked battle of clan members, started right away
+    FriendlyBattle {
+        player1: i32,
+        player2: i32,
+        result_tx: mpsc::Sender<MatchEnded>,
+        tx: Reply,
+    },
+}
+
+pub enum MatchmakerUpdate {
+    Status(QueueStatus),
+    //Matches of challenges and friendly battles start right away, without a deadline
+    MatchFound {
+        m: Match,
+        accept_deadline: Option<DateTime<Utc>>,
+    },
+    //Both players accepted, the battle is created
+    MatchStarted,
+    //Someone declined the match, or did not accept it in time
+    MatchCancelled {
+        requeued: bool,
+    },
+    ChallengeReceived {
+        challenger_id: i32,
+        ranked: bool,
+        deadline: DateTime<Utc>,
+    },
+    //The challenged player declined, or did not answer in time
+    ChallengeDeclined {
+        player_id: i32,
+    },
 }
 
 #[derive(Clone)]
@@ -218,23 +670,285 @@ pub enum BattleMessage {
         player_id: i32,
         animal: DamageAnimal,
     },
-    Response {
-        receivers: Vec<i32>,
-        res: Result<Command, Status>,
+    UsePlayerAbility {
+        player_id: i32,
+        ability: UseAbility,
+    },
+    Surrender {
+        player_id: i32,
+    },
+    Resume {
+        player_id: i32,
+    },
+    GetBattleState {
+        player_id: i32,
+    },
+    //Sent by the battle streams, when they are opened and closed
+    Connected {
+        player_id: i32,
+    },
+    Disconnected {
+        player_id: i32,
+    },
+    //Someone watches the battle of the player
+    Spectate {
+        player_id: i32,
+        tx: SpectatorStream,
     },
 }
 
+impl BattleMessage {
+    pub fn from_client(player_id: i32, message: client_battle_message::Message) -> Self {
+        match message {
+            client_battle_message::Message::Pick(cmd) => BattleMessage::Pick { player_id, cmd },
+            client_battle_message::Message::Ready(_) => BattleMessage::Ready { player_id },
+            client_battle_message::Message::Place(animals) => {
+                BattleMessage::PlacePlayerAnimals { player_id, animals }
+            }
+            client_battle_message::Message::Use(animal) => {
+                BattleMessage::UsePlayerAnimal { player_id, animal }
+            }
+            client_battle_message::Message::Move(animal) => {
+                BattleMessage::MovePlayerAnimal { player_id, animal }
+            }
+            client_battle_message::Message::End(_) => BattleMessage::EndTurn { player_id },
+            client_battle_message::Message::Damage(animal) => {
+                BattleMessage::DamagePlayerAnimal { player_id, animal }
+            }
+            client_battle_message::Message::Ability(ability) => {
+                BattleMessage::UsePlayerAbility { player_id, ability }
+            }
+            client_battle_message::Message::Resume(_) => BattleMessage::Resume { player_id },
+            client_battle_message::Message::GetState(_) => {
+                BattleMessage::GetBattleState { player_id }
+            }
+            client_battle_message::Message::Surrender(_) => BattleMessage::Surrender { player_id },
+        }
+    }
+}
+
+type BattleStream = mpsc::Sender<Result<BattleCommand, Status>>;
+
+//Players in running battles, they cannot start another one until it ends
+#[derive(Clone, Default)]
+pub struct ActivePlayers {
+    players: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl ActivePlayers {
+    pub fn contains(&self, player_id: i32) -> bool {
+        self.players.lock().unwrap().contains(&player_id)
+    }
+
+    pub fn insert(&self, players: &[i32]) {
+        self.players.lock().unwrap().extend(players);
+    }
+
+    pub fn remove(&self, players: &[i32]) {
+        let mut active = self.players.lock().unwrap();
+        for player_id in players {
+            active.remove(player_id);
+        }
+    }
+}
+
+//Battle streams of every player, so commands are delivered only to their receivers
+#[derive(Clone, Default)]
+pub struct Outbound {
+    players: Arc<RwLock<HashMap<i32, Vec<BattleStream>>>>,
+}
+
+impl Outbound {
+    pub fn register(&self, player_id: i32) -> mpsc::Receiver<Result<BattleCommand, Status>> {
+        let (tx, rx) = mpsc::channel(OUTBOUND_CHANNEL_SIZE);
+        let mut players = self.players.write().unwrap();
+        let streams = players.entry(player_id).or_default();
+        streams.retain(|f| !f.is_closed());
+        streams.push(tx);
+        rx
+    }
+
+    //Forgets closed streams of the player
+    pub fn unregister(&self, player_id: i32) {
+        let mut players = self.players.write().unwrap();
+        if let Some(streams) = players.get_mut(&player_id) {
+            streams.retain(|f| !f.is_closed());
+            if streams.is_empty() {
+                players.remove(&player_id);
+            }
+        }
+    }
+
+    //Never waits for a client. A stream with a full buffer is closed instead,
+    //the client has to reconnect and resume the battle to get the actual state
+    pub fn send(&self, receivers: &[i32], res: Result<Command, Status>) {
+        let mut dropped = Vec::new();
+        let players = self.players.read().unwrap();
+        for player_id in receivers {
+            for stream in players.get(player_id).into_iter().flatten() {
+                match stream.try_send(res.clone().map(|cmd| BattleCommand { command: Some(cmd) })) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Battle stream of player {player_id} is full, closing it");
+                        dropped.push((*player_id, stream.clone()));
+                    }
+                    Err(TrySendError::Closed(_)) => dropped.push((*player_id, stream.clone())),
+                }
+            }
+        }
+        drop(players);
+        if dropped.is_empty() {
+            return;
+        }
+
+        //Only the failed streams need the write lock, to be removed
+        let mut players = self.players.write().unwrap();
+        for (player_id, stream) in &dropped {
+            let Some(streams) = players.get_mut(player_id) else {
+                continue;
+            };
+            streams.retain(|f| !f.same_channel(stream));
+            if streams.is_empty() {
+                players.remove(player_id);
+            }
+        }
+    }
+}
+
+pub type SpectatorStream = mpsc::Sender<(Instant, Result<BattleCommand, Status>)>;
+
+//Streams of one battle. Spectators get what both players can see,
+//with the time it happened, so their stream can be delayed.
+//Every command is recorded for the replay
+struct BattleOutbound {
+    outbound: Outbound,
+    players: (i32, i32),
+    spectators: Mutex<Vec<SpectatorStream>>,
+    replay: Mutex<Vec<ReplayCommand>>,
+    //Clock of the battle, replays show its time
+    clock: Arc<dyn Clock + Sync>,
+}
+
+impl BattleOutbound {
+    fn send(&self, receivers: &[i32], res: Result<Command, Status>) {
+        if let Ok(command) = &res {
+            self.record(receivers, command);
+            if let Some(command) = self.spectator_view(receivers, command) {
+                let command = BattleCommand {
+                    command: Some(command),
+                };
+                //Like a player stream, a full one is dropped, the spectator
+                //has to spectate again to get the actual state
+                self.spectators.lock().unwrap().retain(|spectator| {
+                    match spectator.try_send((Instant::now(), Ok(command.clone()))) {
+                        Ok(()) => true,
+                        Err(TrySendError::Full(_)) => {
+                            warn!("Spectator stream is full, dropping it");
+                            false
+                        }
+                        Err(TrySendError::Closed(_)) => false,
+                    }
+                });
+            }
+        }
+        self.outbound.send(receivers, res);
+    }
+
+    //Commands sent to both players, and the opponent's copy of the ones,
+    //which carry hidden information for the acting player
+    fn spectator_view(&self, receivers: &[i32], command: &Command) -> Option<Command> {
+        if self.spectators.lock().unwrap().is_empty() {
+            return None;
+        }
+        let opponent_copy = |player_id: i32| receivers.len() == 1 && receivers[0] != player_id;
+        let both = receivers.contains(&self.players.0) && receivers.contains(&self.players.1);
+        match command {
+            Command::Moved(moved) if receivers.len() == 1 => {
+                opponent_copy(moved.player_id).then(|| {
+                    Command::Moved(AnimalMoved {
+                        squares: None,
+                        action_points: None,
+                        ..moved.clone()
+                    })
+                })
+            }
+            Command::Damaged(damaged) if receivers.len() == 1 => opponent_copy(damaged.player_id)
+                .then(|| {
+                    Command::Damaged(AnimalDamaged {
+                        action_points: None,
+                        ..damaged.clone()
+                    })
+                }),
+            //The opponent's copy has no trap position
+            Command::AbilityUsed(used) if receivers.len() == 1 => opponent_copy(used.player_id)
+                .then(|| {
+                    Command::AbilityUsed(AbilityUsed {
+                        action_points: None,
+                        ..used.clone()
+                    })
+                }),
+            //Every player gets their own glory, spectators get the result once
+            Command::Ended(ended) if receivers == [self.players.0] => {
+                Some(Command::Ended(MatchEnded {
+                    glory_delta: 0,
+                    glory: 0,
+                    ..ended.clone()
+                }))
+            }
+            _ if both => Some(command.clone()),
+            _ => None,
+        }
+    }
+
+    //Snapshots are not state changes, so they are not needed in the replay
+    fn record(&self, receivers: &[i32], command: &Command) {
+        if matches!(command, Command::Snapshot(_)) {
+            return;
+        }
+        let now = self.clock.now();
+        self.replay.lock().unwrap().push(ReplayCommand {
+            time: Some(Timestamp {
+                seconds: now.timestamp(),
+                nanos: now.timestamp_subsec_nanos() as i32,
+            }),
+            receivers: receivers.to_vec(),
+            command: Some(BattleCommand {
+                command: Some(command.clone()),
+            }),
+        });
+    }
+
+    //Forgets spectators, who closed their streams
+    fn add_spectator(&mut self, spectator: SpectatorStream) {
+        let spectators = self.spectators.get_mut().unwrap();
+        spectators.retain(|f| !f.is_closed());
+        spectators.push(spectator);
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-struct Maps {
+pub struct Maps {
     maps: Vec<Map>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Map {
     map_name: String,
+    width: i32,
+    height: i32,
+    //Rows, where the players place their animals
+    player1_zone: Range<i32>,
+    player2_zone: Range<i32>,
+    //How often matchmaking chooses the map, maps with 0 are out of the rotation
+    #[serde(default = "default_map_weight")]
+    weight: u32,
     objects: Vec<MapObject>,
 }
 
+fn default_map_weight() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct MapObject {
     x: i32,
@@ -253,8 +967,39 @@ enum ObjectType {
     Water,
 }
 
+impl ObjectType {
+    //Animals can neither stand on nor walk through such objects
+    fn blocks(&self) -> bool {
+        !matches!(self, ObjectType::CanWalkThrough)
+    }
+}
+
+impl Map {
+    fn on_board(&self, x: i32, y: i32) -> bool {
+        (0..self.width).contains(&x) && (0..self.height).contains(&y)
+    }
+
+    //Players see the board from their own side, so one of them sends flipped rows
+    fn flip(&self, y: i32) -> i32 {
+        self.height - 1 - y
+    }
+
+    fn zone(&self, player2: bool) -> Range<i32> {
+        if player2 {
+            self.player2_zone.clone()
+        } else {
+            self.player1_zone.clone()
+        }
+    }
+
+    //Objects, which take their squares
+    fn obstacles(&self) -> impl Iterator<Item = &MapObject> {
+        self.objects.iter().filter(|f| f.object_type.blocks())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-struct Animals {
+pub struct Animals {
     animals: Vec<Animal>,
 }
 
@@ -303,42 +1048,295 @@ struct Ability {
     target: Option<AbilityTarget>,
 }
 
+impl Maps {
+    pub fn load() -> Self {
+        serde_json::from_str(include_str!("../data/maps.json")).unwrap()
+    }
+}
+
+impl Animals {
+    pub fn load() -> Self {
+        serde_json::from_str(include_str!("../data/animals.json")).unwrap()
+    }
+
+    fn get(&self, id: i32) -> Option<&Animal> {
+        self.animals.iter().find(|f| f.id == id)
+    }
+}
+
+impl Animal {
+    fn passive(&self, name: &str) -> Option<&Ability> {
+        self.abilities
+            .iter()
+            .find(|f| f.name == name && matches!(f.ability_type, AbilityType::Passive))
+    }
+
+    fn active(&self, name: &str) -> Option<&Ability> {
+        self.abilities
+            .iter()
+            .find(|f| f.name == name && matches!(f.ability_type, AbilityType::Active))
+    }
+}
+
 pub async fn run_matchmaking_loop(
     mut rx: Receiver<MatchmakerMessage>,
-    tx: Sender<MatchmakerMessage>,
-    battle_tx: tokio::sync::mpsc::Sender<BattleMessage>,
+    battle_tx: mpsc::Sender<BattleMessage>,
+    outbound: Outbound,
+    content: ContentStore,
+    active: ActivePlayers,
 ) {
-    let mut matchmaker = Matchmaker::new();
-    let maps: Maps = serde_json::from_str(include_str!("../data/maps.json")).unwrap();
+    let rules = BattleRules::load();
+    let mut matchmaker = Matchmaker::default()
+        .with_bot_wait(Some(chrono::Duration::seconds(rules.bot_wait_time as i64)))
+        .with_content(content)
+        .with_active_players(active);
+    let mut subscribers: HashMap<i32, mpsc::Sender<MatchmakerUpdate>> = HashMap::new();
+    let mut challenges: Vec<Challenge> = Vec::new();
     let mut interval = time::interval(Duration::from_secs(1)); // Run the matchmaking algorithm every 1 second
     loop {
         tokio::select! {
             Some(msg) = rx.recv() => {
                 match msg {
                     MatchmakerMessage::JoinMatchmaking { id, rating } => matchmaker.add_player(id, rating),
-                    MatchmakerMessage::LeaveMatchmaking { id } => matchmaker.remove_player(id),
-                    _ => continue
-                }
-            },
-            _ = interval.tick() => {
-                let ids = matchmaker.get_all_ids();
-                for id in ids {
-                    if let Some(m) = matchmaker.find_match(id, &maps) {
-                        matchmaker.remove_player(m.player1);
-                        matchmaker.remove_player(m.player2);
-                        tx.send(MatchmakerMessage::MatchFound(m.clone())).ok();
-                        battle_tx.send(BattleMessage::CreateBattle(m)).await.ok();
+                    MatchmakerMessage::LeaveMatchmaking { id } => {
+                        matchmaker.remove_player(id);
+                        if let Some(mut pending) = matchmaker.decline_match(id) {
+                            //Only the opponent goes back to the queue, without a penalty
+                            pending.players.retain(|(f, _)| *f != id);
+                            pending.accepted = pending.players.iter().map(|(f, _)| *f).collect();
+                            cancel_match(&mut matchmaker, &mut subscribers, pending);
+                        }
+                    }
+                    MatchmakerMessage::Subscribe { id, tx } => {
+                        subscribers.insert(id, tx);
+                    }
+                    MatchmakerMessage::AcceptMatch { id } => {
+                        if let Some(m) = matchmaker.accept_match(id) {
+                            let (player1, player2) = m.players();
+                            //The battle exists before players are told to join it
+                            let animals = m.animals.clone();
+                            battle_tx.send(BattleMessage::CreateBattle(m)).await.ok();
+                            for id in [player1, player2] {
+                                notify(&mut subscribers, id, MatchmakerUpdate::MatchStarted);
+                            }
+                            let bot_id = bot::is_bot(player2).then_some(player2);
+                            //The bot joins the battle like a client, after it is created
+                            if let Some(bot_id) = bot_id {
+                                let bot_rx = outbound.register(bot_id);
+                                let strategy = Box::new(GreedyBot::new(animals, rules.clone()));
+                                let (battle_tx, outbound) = (battle_tx.clone(), outbound.clone());
+                                tokio::spawn(async move {
+                                    bot::run_bot(bot_id, strategy, battle_tx, bot_rx).await;
+                                    outbound.unregister(bot_id);
+                                });
+                            }
+                        }
+                    }
+                    MatchmakerMessage::DeclineMatch { id } => {
+                        if let Some(pending) = matchmaker.decline_match(id) {
+                            cancel_match(&mut matchmaker, &mut subscribers, pending);
+                        }
+                    }
+                    MatchmakerMessage::ChallengePlayer { id, player_id, ranked, tx } => {
+                        if challenges.iter().any(|f| f.challenger_id == id && f.player_id == player_id) {
+                            tx.send(Err(Status::already_exists("Player is already challenged"))).ok();
+                            continue;
+                        }
+                        let deadline = matchmaker.now() + chrono::Duration::seconds(CHALLENGE_TIME as i64);
+                        challenges.push(Challenge { challenger_id: id, player_id, ranked, deadline });
+                        notify(&mut subscribers, player_id, MatchmakerUpdate::ChallengeReceived {
+                            challenger_id: id,
+                            ranked,
+                            deadline,
+                        });
+                        tx.send(Ok(())).ok();
+                    }
+                    MatchmakerMessage::AcceptChallenge { id, challenger_id, tx } => {
+                        let m = match accept_challenge(&mut matchmaker, &mut challenges, id, challenger_id) {
+                            Ok(m) => m,
+                            Err(status) => {
+                                tx.send(Err(status)).ok();
+                                continue;
+                            }
+                        };
+                        start_direct_match(&battle_tx, &mut subscribers, m).await;
+                        tx.send(Ok(())).ok();
+                    }
+                    MatchmakerMessage::FriendlyBattle { player1, player2, result_tx, tx } => {
+                        match matchmaker.direct_match(player1, player2, false) {
+                            Ok(m) => {
+                                let m = Match { result_tx: Some(result_tx), ..m };
+                                start_direct_match(&battle_tx, &mut subscribers, m).await;
+                                tx.send(Ok(())).ok();
+                            }
+                            Err(status) => {
+                                tx.send(Err(status)).ok();
+                            }
+                        }
+                    }
+                    MatchmakerMessage::DeclineChallenge { id, challenger_id, tx } => {
+                        let Some(index) = challenges.iter().position(|f| f.challenger_id == challenger_id && f.player_id == id) else {
+                            tx.send(Err(Status::not_found("Challenge not found"))).ok();
+                            continue;
+                        };
+                        challenges.remove(index);
+                        notify(&mut subscribers, challenger_id, MatchmakerUpdate::ChallengeDeclined { player_id: id });
+                        tx.send(Ok(())).ok();
+                    }
+                }
+            },
+            _ = interval.tick() => {
+                let now = matchmaker.now();
+                let (expired, waiting): (Vec<Challenge>, _) = std::mem::take(&mut challenges)
+                    .into_iter()
+                    .partition(|f| f.deadline <= now);
+                challenges = waiting;
+                for challenge in expired {
+                    notify(&mut subscribers, challenge.challenger_id, MatchmakerUpdate::ChallengeDeclined {
+                        player_id: challenge.player_id,
+                    });
+                }
+                for pending in matchmaker.expired_matches() {
+                    cancel_match(&mut matchmaker, &mut subscribers, pending);
+                }
+                matchmaker.clear_penalties();
+                for (m, accept_deadline) in matchmaker.find_matches() {
+                    for id in [m.player1, m.player2] {
+                        notify(&mut subscribers, id, MatchmakerUpdate::MatchFound {
+                            m: m.clone(),
+                            accept_deadline: Some(accept_deadline),
+                        });
                     }
                 }
+                for (id, status) in matchmaker.queue_status() {
+                    notify(&mut subscribers, id, MatchmakerUpdate::Status(status));
+                }
+                subscribers.retain(|_, f| !f.is_closed());
             }
         }
     }
 }
 
-const PICK_TIME: u64 = 1;
-const PLACE_TIME: u64 = 1;
-const PICK_COUNT: usize = 6;
-const TURN_TIME: u64 = 60;
+fn accept_challenge(
+    matchmaker: &mut Matchmaker,
+    challenges: &mut Vec<Challenge>,
+    id: i32,
+    challenger_id: i32,
+) -> Result<Match, Status> {
+    let index = challenges
+        .iter()
+        .position(|f| f.challenger_id == challenger_id && f.player_id == id)
+        .ok_or(Status::not_found("Challenge not found"))?;
+    let m = matchmaker.direct_match(challenger_id, id, challenges[index].ranked)?;
+    challenges.remove(index);
+    Ok(m)
+}
+
+//Creates the battle of a match, which needs no accepting.
+//Players still get the match, to learn the map and their side of it
+async fn start_direct_match(
+    battle_tx: &mpsc::Sender<BattleMessage>,
+    subscribers: &mut HashMap<i32, mpsc::Sender<MatchmakerUpdate>>,
+    m: Match,
+) {
+    battle_tx
+        .send(BattleMessage::CreateBattle(m.clone()))
+        .await
+        .ok();
+    for id in [m.player1, m.player2] {
+        notify(
+            subscribers,
+            id,
+            MatchmakerUpdate::MatchFound {
+                m: m.clone(),
+                accept_deadline: None,
+            },
+        );
+        notify(subscribers, id, MatchmakerUpdate::MatchStarted);
+    }
+}
+
+fn cancel_match(
+    matchmaker: &mut Matchmaker,
+    subscribers: &mut HashMap<i32, mpsc::Sender<MatchmakerUpdate>>,
+    pending: PendingMatch,
+) {
+    for (id, requeued) in matchmaker.cancel_match(pending) {
+        notify(
+            subscribers,
+            id,
+            MatchmakerUpdate::MatchCancelled { requeued },
+        );
+    }
+}
+
+//Never waits for a subscriber. A slow one misses statuses,
+//but if it cannot get its match the stream is closed
+fn notify(
+    subscribers: &mut HashMap<i32, mpsc::Sender<MatchmakerUpdate>>,
+    id: i32,
+    update: MatchmakerUpdate,
+) {
+    let Some(tx) = subscribers.get(&id) else {
+        return;
+    };
+    match tx.try_send(update) {
+        Ok(()) | Err(TrySendError::Full(MatchmakerUpdate::Status(_))) => {}
+        Err(TrySendError::Full(_)) => {
+            warn!("Matchmaking stream of player {id} is full, closing it");
+            subscribers.remove(&id);
+        }
+        Err(TrySendError::Closed(_)) => {
+            subscribers.remove(&id);
+        }
+    }
+}
+
+const ACCEPT_TIME: u64 = 10;
+const CHALLENGE_TIME: u64 = 60;
+const DECLINE_PENALTY_TIME: u64 = 30;
+const IDLE_TICK_TIME: u64 = 1;
+const BATTLE_CHANNEL_SIZE: usize = 32;
+const OUTBOUND_CHANNEL_SIZE: usize = 128;
+const MOVE_AP_COST: f32 = 2f32;
+const ATTACK_AP_COST: f32 = 10f32;
+
+//Abilities
+const POUNCE_DISTANCE: i32 = 4;
+const POUNCE_EXTRA_DAMAGE: i32 = 10;
+const DEEP_BITE_PERCENTS: i32 = 30;
+const GNAW_DAMAGE: i32 = 13;
+const CHARGE_DAMAGE: i32 = 40;
+const EGG_DAMAGE: i32 = 10;
+const TRAP_DAMAGE: i32 = 35;
+const BURROW_HEAL_PERCENTS: i32 = 70;
+const CLUCK_AP: f32 = 20f32;
+const CLUCK_DISTANCE: i32 = 3;
+const NINE_LIVES_HP_PERCENTS: i32 = 10;
+const SLY_HIT_PERIOD: i32 = 3;
+
+//Effects, (turns, value)
+const POUNCE_STUN: (i32, i32) = (1, 0);
+const SCRATCH_BLEED: (i32, i32) = (4, 5);
+const PERSISTANCE_BLEED: (i32, i32) = (2, 5);
+const PERSISTANCE_HIT_PERIOD: i32 = 3;
+const FLUTTER_STEPS: (i32, i32) = (2, 5);
+const FLUTTER_SHIELD: (i32, i32) = (2, 40);
+const EGG_STUN: (i32, i32) = (1, 0);
+const CLUCK_BOOST: (i32, i32) = (3, 20);
+const CATS_EYE_SIGHT: (i32, i32) = (5, 0);
+const SNEAK_INVISIBILITY: (i32, i32) = (1, 0);
+const GNAW_WEAKNESS: (i32, i32) = (2, 25);
+const CHEESE_LURE_STUN: (i32, i32) = (1, 0);
+const CHEESE_LURE_RADIUS: i32 = 5;
+const MUD_PATCH_DAMAGE: (i32, i32) = (3, 7);
+const MUD_PATCH_SLOW_PERCENTS: i32 = 30;
+const SNORT_STUN: (i32, i32) = (1, 0);
+const SNORT_DISTANCE: i32 = 3;
+const BITE_SLOW: (i32, i32) = (2, 3);
+const BURROW_INVULNERABILITY: (i32, i32) = (1, 100);
+const CHARM_PACIFY: (i32, i32) = (2, 0);
+const THICK_SKIN_PERCENTS: i32 = 50;
 
 #[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Set {
@@ -348,119 +1346,513 @@ pub enum Set {
     EndTurn,
 }
 
-pub async fn run_battles_loop(mut rx: Receiver<BattleMessage>, tx: Sender<BattleMessage>) {
-    let animals: Arc<Animals> =
-        Arc::new(serde_json::from_str(include_str!("../data/animals.json")).unwrap());
+pub async fn run_battles_loop(
+    mut rx: Receiver<BattleMessage>,
+    tx: Outbound,
+    active: ActivePlayers,
+    pool: Pool<Postgres>,
+) {
+    let rules = BattleRules::load();
     let mut index_map = HashMap::new();
-    let mut worlds = Vec::new();
-    let mut interval = time::interval(Duration::from_secs(1));
-    let mut schedule = Schedule::default();
-    schedule.set_executor_kind(ExecutorKind::Simple);
-
-    schedule.add_system(Events::<Event>::update_system.in_set(Set::FlushEvents));
-    schedule.add_systems(
-        (pick_timeout, ready, pick, place, place_timeout)
-            .in_set(Set::Preparations)
-            .after(Set::FlushEvents),
-    );
-    schedule.add_systems(
-        (use_animal, move_animal, turn_timeout, end_turn, damage)
-            .in_set(Set::Gameplay)
-            .after(Set::Preparations),
-    );
-    schedule.add_system(death.in_set(Set::EndTurn).after(Set::Gameplay));
+    //Every battle runs on its own task, messages are routed to it through a channel
+    let mut battles: HashMap<usize, mpsc::Sender<BattleMessage>> = HashMap::new();
+    let mut next_index = 0usize;
+    let (finished_tx, mut finished_rx) = mpsc::channel::<usize>(128);
+    //Number of open battle streams of every player
+    let mut connections: HashMap<i32, usize> = HashMap::new();
     loop {
         tokio::select! {
             Some(msg) = rx.recv() => {
                 match msg {
                     BattleMessage::Ready { player_id } => {
-                        if let Some(&index) = index_map.get(&player_id)
-                        {
-                            let world: &mut World = &mut worlds[index];
-                            world.send_event(Event {
-                                message: msg
-                            });
-                            schedule.run(world);
+                        route(&battles, &index_map, player_id, msg).ok();
+                    },
+                    BattleMessage::Connected { player_id } => {
+                        let count = connections.entry(player_id).or_default();
+                        *count += 1;
+                        if *count == 1 {
+                            route(&battles, &index_map, player_id, msg).ok();
+                        }
+                    },
+                    BattleMessage::Disconnected { player_id } => {
+                        if let Some(count) = connections.get_mut(&player_id) {
+                            *count -= 1;
+                            if *count == 0 {
+                                connections.remove(&player_id);
+                                route(&battles, &index_map, player_id, msg).ok();
+                            }
                         }
                     },
                     BattleMessage::CreateBattle(m) => {
-                        let mut world = World::new();
-                        index_map.insert(m.player1, worlds.len());
-                        index_map.insert(m.player2, worlds.len());
-                        world.insert_resource(Events::<Event>::default());
-                        for object in &m.map.objects {
-                            world.spawn(Position {
-                                x: object.x,
-                                y: object.y
-                            });
+                        let players = [m.player1, m.player2];
+                        index_map.insert(m.player1, next_index);
+                        index_map.insert(m.player2, next_index);
+                        let battle = Battle::new(
+                            m,
+                            tx.clone(),
+                            rules.clone(),
+                            StdRng::from_entropy(),
+                            Box::new(SystemClock),
+                        );
+                        let (battle_tx, battle_rx) = mpsc::channel(BATTLE_CHANNEL_SIZE);
+                        battles.insert(next_index, battle_tx);
+                        //Streams, which are already open, are not opened again
+                        for player_id in players.into_iter().filter(|f| connections.contains_key(f)) {
+                            route(&battles, &index_map, player_id, BattleMessage::Connected { player_id }).ok();
                         }
-                        world.insert_resource(GameState::new(m, tx.clone(), animals.clone()));
-                        worlds.push(world);
+                        tokio::spawn(run_battle(battle, next_index, battle_rx, finished_tx.clone(), active.clone(), pool.clone()));
+                        next_index += 1;
                     }
                     BattleMessage::Pick { player_id, cmd: _ }
                     | BattleMessage::PlacePlayerAnimals { player_id, animals: _ }
                     | BattleMessage::UsePlayerAnimal { player_id, animal: _ }
                     | BattleMessage::MovePlayerAnimal { player_id, animal: _ }
                     | BattleMessage::EndTurn { player_id }
-                    | BattleMessage::DamagePlayerAnimal { player_id, animal: _ } => {
-                        if let Some(&index) = index_map.get(&player_id)
-                        {
-                            let world: &mut World = &mut worlds[index];
-                            world.send_event(Event {
-                                message: msg
-                            });
-                            schedule.run(world);
-                        } else {
-                            tx.send(BattleMessage::Response{
-                                receivers: vec![player_id],
-                                res: Err(Status::not_found(
-                                    "Player is not playing",
-                                ))
-                            })
-                            .ok();
+                    | BattleMessage::DamagePlayerAnimal { player_id, animal: _ }
+                    | BattleMessage::UsePlayerAbility { player_id, ability: _ }
+                    | BattleMessage::Surrender { player_id }
+                    | BattleMessage::Resume { player_id }
+                    | BattleMessage::GetBattleState { player_id } => {
+                        if let Err(status) = route(&battles, &index_map, player_id, msg) {
+                            tx.send(&[player_id], Err(status));
                         }
                     },
-                    BattleMessage::Response { receivers: _, res: _ } => continue,
+                    BattleMessage::Spectate { player_id, ref tx } => {
+                        let spectator = tx.clone();
+                        if let Err(status) = route(&battles, &index_map, player_id, msg) {
+                            spectator.try_send((Instant::now(), Err(status))).ok();
+                        }
+                    }
                 }
             },
-            _ = interval.tick() => {
-                for world in &mut worlds {
-                    schedule.run(world);
-                }
+            //The players are already freed by the battle
+            Some(index) = finished_rx.recv() => {
+                battles.remove(&index);
+                index_map.retain(|_, f| *f != index);
             }
         }
     }
 }
 
+//Sends the message to the battle of the player without waiting, so a busy battle does not block others
+fn route(
+    battles: &HashMap<usize, mpsc::Sender<BattleMessage>>,
+    index_map: &HashMap<i32, usize>,
+    player_id: i32,
+    message: BattleMessage,
+) -> Result<(), Status> {
+    let battle = index_map
+        .get(&player_id)
+        .and_then(|index| battles.get(index))
+        .ok_or_else(|| Status::not_found("Player is not playing"))?;
+    battle.try_send(message).map_err(|e| match e {
+        TrySendError::Full(_) => Status::resource_exhausted("Battle is busy, try again later"),
+        TrySendError::Closed(_) => Status::not_found("Player is not playing"),
+    })
+}
+
+//Owns the world of one battle, runs it on every message and when its nearest deadline comes
+async fn run_battle(
+    mut battle: Battle,
+    index: usize,
+    mut rx: mpsc::Receiver<BattleMessage>,
+    finished_tx: mpsc::Sender<usize>,
+    active: ActivePlayers,
+    pool: Pool<Postgres>,
+) {
+    loop {
+        let wait = battle.next_deadline();
+        let message = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(message) => Some(message),
+                None => return,
+            },
+            _ = time::sleep(wait) => None
+        };
+        battle.run(message);
+        if battle.is_finished() {
+            end_battle(battle.world, &active, &pool).await;
+            finished_tx.send(index).await.ok();
+            return;
+        }
+    }
+}
+
+//Randomness of the battle, tests fix the seed
+#[derive(Resource)]
+pub struct BattleRng(pub StdRng);
+
+//Time of the battle, tests move it by hand
+#[derive(Resource)]
+pub struct BattleClock(pub Arc<dyn Clock + Sync>);
+
+impl BattleClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0.now()
+    }
+}
+
+//World of one battle with its systems.
+//The same seed, clock and messages always give the same battle
+pub struct Battle {
+    world: World,
+    schedule: Schedule,
+}
+
+impl Battle {
+    pub fn new(
+        m: Match,
+        tx: Outbound,
+        rules: BattleRules,
+        mut rng: StdRng,
+        clock: Box<dyn Clock + Sync>,
+    ) -> Self {
+        let mut world = World::new();
+        world.insert_resource(Events::<Event>::default());
+        //Walkable objects are only drawn, they do not take squares
+        for object in m.map.obstacles() {
+            world.spawn(Position {
+                x: object.x,
+                y: object.y,
+            });
+        }
+        let clock: Arc<dyn Clock + Sync> = Arc::from(clock);
+        let mut state = GameState::new(m, tx, &mut rng, clock.clone());
+        //Players, who never open their battle stream, forfeit like disconnected ones
+        let deadline = state.started_at + chrono::Duration::seconds(rules.connect_time as i64);
+        for player_id in [state.m.player1, state.m.player2] {
+            state.disconnected.insert(player_id, deadline);
+        }
+        world.insert_resource(state);
+        world.insert_resource(BattleRng(rng));
+        world.insert_resource(BattleClock(clock));
+        world.insert_resource(rules);
+        Self {
+            world,
+            schedule: battle_schedule(),
+        }
+    }
+
+    //Runs the systems once, with the message or just to check the deadlines
+    pub fn run(&mut self, message: Option<BattleMessage>) {
+        if let Some(message) = message {
+            self.world.send_event(Event { message });
+        }
+        self.schedule.run(&mut self.world);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.world.resource::<GameState>().state == BattleState::Finished
+    }
+
+    //None for a draw or a battle, that is not finished
+    pub fn winner(&self) -> Option<i32> {
+        self.world
+            .resource::<GameState>()
+            .result
+            .as_ref()
+            .and_then(|f| f.winner)
+    }
+
+    //Time until the nearest timeout of the battle
+    fn next_deadline(&self) -> Duration {
+        let state = self.world.resource::<GameState>();
+        let deadline = state
+            .disconnected
+            .values()
+            .fold(state.deadline, |acc, f| acc.min(*f));
+        //Passed deadlines may wait for something else, like ready players
+        (deadline - self.world.resource::<BattleClock>().now())
+            .to_std()
+            .unwrap_or(Duration::from_secs(IDLE_TICK_TIME))
+    }
+}
+
+pub fn battle_schedule() -> Schedule {
+    let mut schedule = Schedule::default();
+    schedule.set_executor_kind(ExecutorKind::Simple);
+
+    schedule.add_system(Events::<Event>::update_system.in_set(Set::FlushEvents));
+    schedule.add_systems(
+        (pick_timeout, ready, pick, place, place_timeout)
+            .in_set(Set::Preparations)
+            .after(Set::FlushEvents),
+    );
+    schedule.add_systems(
+        (
+            use_animal,
+            move_animal,
+            turn_timeout,
+            end_turn,
+            damage,
+            use_ability,
+        )
+            .in_set(Set::Gameplay)
+            .after(Set::Preparations),
+    );
+    schedule.add_systems(
+        (apply_effects, death, connection, victory, snapshot)
+            .chain()
+            .in_set(Set::EndTurn)
+            .after(Set::Gameplay),
+    );
+    schedule
+}
+
+//Saves the match, frees the players and sends them the result.
+//Players are freed after the save, so their next match uses the new ratings
+async fn end_battle(mut world: World, active: &ActivePlayers, pool: &Pool<Postgres>) {
+    let finished_at = world.resource::<BattleClock>().now();
+    let GameState {
+        m,
+        tx,
+        result,
+        picked,
+        started_at,
+        ..
+    } = world.remove_resource::<GameState>().unwrap();
+
+    let result = result.unwrap();
+    //The result without glory, that is different for the players
+    tx.record(
+        &[m.player1, m.player2],
+        &Command::Ended(MatchEnded {
+            winner_id: result.winner,
+            reason: result.reason.into(),
+            glory_delta: 0,
+            glory: 0,
+        }),
+    );
+    let replay = Replay {
+        match_id: 0,
+        player1_id: m.player1,
+        player2_id: m.player2,
+        map: Some(m.map.clone().into()),
+        commands: std::mem::take(&mut tx.replay.lock().unwrap()),
+    };
+    let changes =
+        match save_match(pool, &m, &result, &picked, started_at, finished_at, &replay).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Failed to save match: {e}");
+                Vec::new()
+            }
+        };
+    active.remove(&[m.player1, m.player2]);
+    if let Some(result_tx) = &m.result_tx {
+        result_tx
+            .send(MatchEnded {
+                winner_id: result.winner,
+                reason: result.reason.into(),
+                glory_delta: 0,
+                glory: 0,
+            })
+            .await
+            .ok();
+    }
+    for player_id in [m.player1, m.player2] {
+        let (glory, glory_delta) = changes
+            .iter()
+            .find(|f| f.0 == player_id)
+            .map(|f| (f.1, f.2))
+            .unwrap_or_default();
+        tx.send(
+            &[player_id],
+            Ok(Command::Ended(MatchEnded {
+                winner_id: result.winner,
+                reason: result.reason.into(),
+                glory_delta,
+                glory,
+            })),
+        );
+    }
+}
+
+//Updates ratings and writes the match into history in one transaction
+async fn save_match(
+    pool: &Pool<Postgres>,
+    m: &Match,
+    result: &MatchResult,
+    picked: &[(i32, i32)],
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    replay: &Replay,
+) -> Result<Vec<(i32, i32, i32)>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let changes = update_ratings(&mut transaction, m, result).await?;
+    //Bots are not players, so the battle is not stored
+    if bot::is_bot(m.player1) || bot::is_bot(m.player2) {
+        transaction.commit().await?;
+        return Ok(changes);
+    }
+
+    let animals = |player_id: i32| {
+        picked
+            .iter()
+            .filter(|f| f.0 == player_id)
+            .map(|f| f.1)
+            .collect::<Vec<i32>>()
+    };
+    let glory_delta = |player_id: i32| {
+        changes
+            .iter()
+            .find(|f| f.0 == player_id)
+            .map(|f| f.2)
+            .unwrap_or_default()
+    };
+    let (match_id,): (i32,) = sqlx::query_as(
+        "INSERT INTO matches (player1_id, player2_id, map_name, player1_animals, player2_animals,
+                              winner_id, end_reason, player1_glory_delta, player2_glory_delta,
+                              started_at, finished_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        RETURNING id",
+    )
+    .bind(m.player1)
+    .bind(m.player2)
+    .bind(&m.map.map_name)
+    .bind(animals(m.player1))
+    .bind(animals(m.player2))
+    .bind(result.winner)
+    .bind(SqlMatchEndReason::from(result.reason))
+    .bind(glory_delta(m.player1))
+    .bind(glory_delta(m.player2))
+    .bind(started_at)
+    .bind(finished_at)
+    .fetch_one(&mut transaction)
+    .await?;
+
+    sqlx::query("INSERT INTO replays (match_id, replay) VALUES ($1, $2)")
+        .bind(match_id)
+        .bind(replay.encode_to_vec())
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(changes)
+}
+
+//Returns (player id, new glory, glory delta) for both players.
+//Glory stays the same after unranked battles
+async fn update_ratings(
+    transaction: &mut Transaction<'_, Postgres>,
+    m: &Match,
+    result: &MatchResult,
+) -> Result<Vec<(i32, i32, i32)>, sqlx::Error> {
+    let rows: Vec<(i32, i32, f64)> = sqlx::query_as(
+        "SELECT id, glory, deviation
+        FROM players
+        WHERE id IN ($1, $2)
+        FOR UPDATE",
+    )
+    .bind(m.player1)
+    .bind(m.player2)
+    .fetch_all(&mut *transaction)
+    .await?;
+    if !m.ranked {
+        return Ok(rows.iter().map(|f| (f.0, f.1, 0)).collect());
+    }
+
+    let rating = |player_id: i32| {
+        rows.iter()
+            .find(|f| f.0 == player_id)
+            .map(|&(_, glory, deviation)| StickoRating {
+                rating: glory as f64,
+                deviation,
+            })
+            .ok_or(sqlx::Error::RowNotFound)
+    };
+    let (player1, player2) = (rating(m.player1)?, rating(m.player2)?);
+    let outcome = match result.winner {
+        Some(winner) if winner == m.player1 => Outcomes::WIN,
+        Some(_) => Outcomes::LOSS,
+        None => Outcomes::DRAW,
+    };
+    let (new_player1, new_player2) = sticko(&player1, &player2, &outcome, &StickoConfig::new());
+
+    let mut changes = Vec::with_capacity(2);
+    for (player_id, old, new) in [
+        (m.player1, player1, new_player1),
+        (m.player2, player2, new_player2),
+    ] {
+        //Glory is shown to players, so it never goes below zero
+        let glory = new.rating.max(0f64).round() as i32;
+        sqlx::query("UPDATE players SET glory = $1, deviation = $2 WHERE id = $3")
+            .bind(glory)
+            .bind(new.deviation)
+            .bind(player_id)
+            .execute(&mut *transaction)
+            .await?;
+        changes.push((player_id, glory, glory - old.rating as i32));
+    }
+    Ok(changes)
+}
+
 //Resources
 
+#[derive(Clone)]
+struct MatchResult {
+    winner: Option<i32>,
+    reason: MatchEndReason,
+}
+
 #[derive(Resource)]
 struct GameState {
     state: BattleState,
     current_turn: i32,
+    turns: i32,
     m: Match,
-    tx: Sender<BattleMessage>,
+    tx: BattleOutbound,
     deadline: DateTime<Utc>,
     animals: Arc<Animals>,
+    result: Option<MatchResult>,
+    picked: Vec<(i32, i32)>,
+    started_at: DateTime<Utc>,
+    //Players without a battle stream, and when they forfeit
+    disconnected: HashMap<i32, DateTime<Utc>>,
 }
 
 impl GameState {
-    fn new(m: Match, tx: Sender<BattleMessage>, animals: Arc<Animals>) -> Self {
+    fn new(m: Match, tx: Outbound, rng: &mut StdRng, clock: Arc<dyn Clock + Sync>) -> Self {
+        let animals = m.animals.clone();
+        let now = clock.now();
+        let tx = BattleOutbound {
+            outbound: tx,
+            players: (m.player1, m.player2),
+            spectators: Mutex::new(Vec::new()),
+            replay: Mutex::new(Vec::new()),
+            clock,
+        };
         Self {
             state: BattleState::PickStage,
-            current_turn: if rand::thread_rng().gen_range(0..=1) == 0 {
+            current_turn: if rng.gen_range(0..=1) == 0 {
                 m.player1
             } else {
                 m.player2
             },
+            turns: 0,
             m,
             tx,
-            deadline: Utc::now(),
+            deadline: now,
             animals,
+            result: None,
+            picked: Vec::new(),
+            started_at: now,
+            disconnected: HashMap::new(),
+        }
+    }
+
+    fn opponent(&self, player_id: i32) -> i32 {
+        if self.m.player1 == player_id {
+            self.m.player2
+        } else {
+            self.m.player1
         }
     }
 
     fn next_turn(&mut self) {
+        if self.state == BattleState::GameStage {
+            self.turns += 1;
+        }
         if self.state != BattleState::PlacementStage {
             self.current_turn = if self.current_turn == self.m.player1 {
                 self.m.player2
@@ -505,6 +1897,14 @@ struct Position {
 }
 
 impl Position {
+    fn on_board(&self, map: &Map) -> bool {
+        map.on_board(self.x, self.y)
+    }
+
+    fn distance(&self, other: &Position) -> i32 {
+        (self.x - other.x).abs() + (self.y - other.y).abs()
+    }
+
     fn can_hit(&self, other: &Position) -> bool {
         println!("{self:?}, {other:?}");
         if self.x == other.x {
@@ -544,6 +1944,12 @@ struct HitDamageBlock {
     percents: f32,
 }
 
+impl HitDamageBlock {
+    fn reduce(&self, amount: i32) -> i32 {
+        ((1f32 - self.percents / 100f32) * amount as f32) as i32
+    }
+}
+
 #[derive(Component, Clone)]
 struct Mobility {
     squares: i32,
@@ -559,6 +1965,125 @@ struct APRecovery {
     amount: f32,
 }
 
+#[derive(Component, Clone, Default)]
+struct Cooldowns {
+    turns: HashMap<String, i32>,
+}
+
+impl Cooldowns {
+    fn get(&self, ability: &str) -> i32 {
+        self.turns.get(ability).copied().unwrap_or(0)
+    }
+
+    fn is_ready(&self, ability: &str) -> bool {
+        self.get(ability) <= 0
+    }
+
+    fn start(&mut self, ability: &str, turns: i32) {
+        self.turns.insert(ability.to_string(), turns);
+    }
+
+    fn tick(&mut self) {
+        for turns in self.turns.values_mut() {
+            *turns = (*turns - 1).max(0);
+        }
+    }
+}
+
+#[derive(Component, Clone, Default)]
+struct HitsTaken {
+    count: i32,
+}
+
+#[derive(Component)]
+struct Egg {
+    player_id: i32,
+    animal_id: i32,
+}
+
+//Traps are invisible for the opponent and do not block squares, so they have no Position
+#[derive(Component)]
+struct Trap {
+    player_id: i32,
+    animal_id: i32,
+    x: i32,
+    y: i32,
+}
+
+//Which animal the hits of the Persistance ability are counted on
+#[derive(Component, Clone, Default)]
+struct HitStreak {
+    target: i32,
+    count: i32,
+}
+
+#[derive(Clone)]
+struct Effect {
+    effect: EffectType,
+    turns: i32,
+    value: i32,
+    //Player and animal, who applied the effect
+    source: (i32, i32),
+    //Change of the animal stats, which is reverted on expiration
+    delta: i32,
+}
+
+#[derive(Component, Clone, Default)]
+struct Effects {
+    active: Vec<Effect>,
+    //Applied by the apply_effects system
+    pending: Vec<Effect>,
+}
+
+impl Effects {
+    fn add(&mut self, effect: EffectType, (turns, value): (i32, i32), source: &AnimalId) {
+        self.pending.push(Effect {
+            effect,
+            turns,
+            value,
+            source: (source.player_id, source.id),
+            delta: 0,
+        });
+    }
+
+    fn has(&self, effect: EffectType) -> bool {
+        self.active.iter().any(|f| f.effect == effect)
+    }
+
+    fn mobility_bonus(&self) -> i32 {
+        self.active
+            .iter()
+            .map(|f| match f.effect {
+                EffectType::ExtraSteps => f.value,
+                EffectType::Slow => -f.value,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn check_action(&self) -> Result<(), Status> {
+        if self.has(EffectType::Stun) {
+            return Err(Status::permission_denied("Animal is stunned"));
+        }
+        Ok(())
+    }
+
+    fn check_attack(&self) -> Result<(), Status> {
+        self.check_action()?;
+        if self.has(EffectType::Pacified) {
+            return Err(Status::permission_denied("Animal is pacified"));
+        }
+        Ok(())
+    }
+
+    //Attacks reveal invisibility
+    fn reveal(&mut self) -> bool {
+        let len = self.active.len();
+        self.active.retain(|f| f.effect != EffectType::Invisible);
+        len != self.active.len()
+    }
+}
+
 #[derive(Bundle)]
 struct AnimalCharacteristics {
     id: AnimalId,
@@ -568,18 +2093,60 @@ struct AnimalCharacteristics {
     mobility: Mobility,
     ap: ActionPoints,
     ap_recovery: APRecovery,
+    cooldowns: Cooldowns,
+    hits_taken: HitsTaken,
+    streak: HitStreak,
+    effects: Effects,
+}
+
+impl AnimalCharacteristics {
+    fn new(animal: &Animal, player_id: i32) -> Self {
+        Self {
+            id: AnimalId {
+                id: animal.id,
+                player_id,
+            },
+            health: Health { amount: animal.hp },
+            damage: HitDamage {
+                amount: animal.damage,
+            },
+            block: HitDamageBlock {
+                percents: animal.resistance,
+            },
+            mobility: Mobility {
+                squares: animal.mobility,
+            },
+            ap: ActionPoints {
+                amount: animal.action_points as f32,
+            },
+            ap_recovery: APRecovery {
+                amount: animal.action_points_per_turn,
+            },
+            cooldowns: Cooldowns::default(),
+            hits_taken: HitsTaken::default(),
+            streak: HitStreak::default(),
+            effects: Effects::default(),
+        }
+    }
 }
 
 //Systems
 
-fn pick_timeout(mut state: ResMut<GameState>, mut commands: Commands, query: Query<&AnimalId>) {
+fn pick_timeout(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
+    mut rng: ResMut<BattleRng>,
+    mut state: ResMut<GameState>,
+    mut commands: Commands,
+    query: Query<&AnimalId>,
+) {
     if state.state != BattleState::PickStage {
         return;
     }
-    let now = Utc::now();
+    let now = clock.now();
     if state.all_ready()
         && (state.deadline - now).num_milliseconds() <= 0
-        && query.iter().count() != PICK_COUNT
+        && query.iter().count() != rules.pick_count()
     {
         let turn = state.current_turn;
 
@@ -593,93 +2160,69 @@ fn pick_timeout(mut state: ResMut<GameState>, mut commands: Commands, query: Que
             .iter()
             .filter(|f| query.iter().all(|g| g.id != f.id))
             .collect();
-        let animal = *available_animals.choose(&mut rand::thread_rng()).unwrap();
+        let animal = *available_animals.choose(&mut rng.0).unwrap();
 
-        commands.spawn(AnimalCharacteristics {
-            id: AnimalId {
-                id: animal.id,
+        commands.spawn(AnimalCharacteristics::new(animal, turn));
+
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::Picked(AnimalPicked {
+                animal_id: animal.id,
                 player_id: turn,
-            },
-            health: Health { amount: animal.hp },
-            damage: HitDamage {
-                amount: animal.damage,
-            },
-            block: HitDamageBlock {
-                percents: animal.resistance,
-            },
-            mobility: Mobility {
-                squares: animal.mobility,
-            },
-            ap: ActionPoints {
-                amount: animal.action_points as f32,
-            },
-            ap_recovery: APRecovery {
-                amount: animal.action_points_per_turn,
-            },
-        });
-
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::Picked(AnimalPicked {
-                    animal_id: animal.id,
-                    player_id: turn,
+            })),
+        );
+        let picked = (turn, animal.id);
+        state.picked.push(picked);
+
+        if query.iter().count() == rules.pick_count() - 1 {
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::SetState(SetBattleState {
+                    state: BattleState::PlacementStage.into(),
                 })),
-            })
-            .ok();
-
-        if query.iter().count() == PICK_COUNT - 1 {
-            state
-                .tx
-                .send(BattleMessage::Response {
-                    receivers: vec![state.m.player1, state.m.player2],
-                    res: Ok(Command::SetState(SetBattleState {
-                        state: BattleState::PlacementStage.into(),
-                    })),
-                })
-                .ok();
+            );
             state.state = BattleState::PlacementStage;
             state.deadline = DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp_opt(now.timestamp() + PLACE_TIME as i64, 0).unwrap(),
+                NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.place_time as i64, 0)
+                    .unwrap(),
                 Utc,
             );
-            state
-                .tx
-                .send(BattleMessage::Response {
-                    receivers: vec![state.m.player1, state.m.player2],
-                    res: Ok(Command::TurnToPick(TurnToPick {
-                        player_id: None,
-                        deadline: Some(Timestamp {
-                            seconds: state.deadline.timestamp(),
-                            nanos: 0,
-                        }),
-                    })),
-                })
-                .ok();
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::TurnToPick(TurnToPick {
+                    player_id: None,
+                    deadline: Some(Timestamp {
+                        seconds: state.deadline.timestamp(),
+                        nanos: 0,
+                    }),
+                })),
+            );
         } else {
             state.deadline = DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp_opt(now.timestamp() + PICK_TIME as i64, 0).unwrap(),
+                NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.pick_time as i64, 0)
+                    .unwrap(),
                 Utc,
             );
-            state
-                .tx
-                .send(BattleMessage::Response {
-                    receivers: vec![state.m.player1, state.m.player2],
-                    res: Ok(Command::TurnToPick(TurnToPick {
-                        player_id: Some(state.current_turn),
-                        deadline: Some(Timestamp {
-                            seconds: state.deadline.timestamp(),
-                            nanos: 0,
-                        }),
-                    })),
-                })
-                .ok();
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::TurnToPick(TurnToPick {
+                    player_id: Some(state.current_turn),
+                    deadline: Some(Timestamp {
+                        seconds: state.deadline.timestamp(),
+                        nanos: 0,
+                    }),
+                })),
+            );
         }
     }
 }
 
-fn ready(mut state: ResMut<GameState>, mut event_reader: EventReader<Event>) {
+fn ready(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
+    mut state: ResMut<GameState>,
+    mut event_reader: EventReader<Event>,
+) {
     if state.state != BattleState::PickStage {
         return;
     }
@@ -688,22 +2231,19 @@ fn ready(mut state: ResMut<GameState>, mut event_reader: EventReader<Event>) {
             state.set_ready(player_id);
 
             if state.all_ready() {
-                let now = Utc::now();
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![state.m.player1, state.m.player2],
-                        res: Ok(Command::TurnToPick(TurnToPick {
-                            player_id: Some(state.current_turn),
-                            deadline: Some(Timestamp {
-                                seconds: now.timestamp() + PICK_TIME as i64,
-                                nanos: 0,
-                            }),
-                        })),
-                    })
-                    .ok();
+                let now = clock.now();
+                state.tx.send(
+                    &[state.m.player1, state.m.player2],
+                    Ok(Command::TurnToPick(TurnToPick {
+                        player_id: Some(state.current_turn),
+                        deadline: Some(Timestamp {
+                            seconds: now.timestamp() + rules.pick_time as i64,
+                            nanos: 0,
+                        }),
+                    })),
+                );
                 state.deadline = DateTime::<Utc>::from_utc(
-                    NaiveDateTime::from_timestamp_opt(now.timestamp() + PICK_TIME as i64, 0)
+                    NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.pick_time as i64, 0)
                         .unwrap(),
                     Utc,
                 );
@@ -713,6 +2253,8 @@ fn ready(mut state: ResMut<GameState>, mut event_reader: EventReader<Event>) {
 }
 
 fn pick(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
     mut state: ResMut<GameState>,
     mut event_reader: EventReader<Event>,
     mut commands: Commands,
@@ -731,7 +2273,7 @@ fn pick(
             if state.animals.animals.iter().any(|f| f.id == animal_id)
                 && query.iter().all(|f| f.id != animal_id)
                 && state.current_turn == player_id
-                && query.iter().count() != PICK_COUNT
+                && query.iter().count() != rules.pick_count()
             {
                 let animal = state
                     .animals
@@ -740,108 +2282,82 @@ fn pick(
                     .find(|f| f.id == animal_id)
                     .unwrap();
 
-                commands.spawn(AnimalCharacteristics {
-                    id: AnimalId {
-                        id: animal.id,
-                        player_id,
-                    },
-                    health: Health { amount: animal.hp },
-                    damage: HitDamage {
-                        amount: animal.damage,
-                    },
-                    block: HitDamageBlock {
-                        percents: animal.resistance,
-                    },
-                    mobility: Mobility {
-                        squares: animal.mobility,
-                    },
-                    ap: ActionPoints {
-                        amount: animal.action_points as f32,
-                    },
-                    ap_recovery: APRecovery {
-                        amount: animal.action_points_per_turn,
-                    },
-                });
+                commands.spawn(AnimalCharacteristics::new(animal, player_id));
+                state.picked.push((player_id, animal_id));
 
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![state.m.player1, state.m.player2],
-                        res: Ok(Command::Picked(AnimalPicked {
-                            animal_id,
-                            player_id: state.current_turn,
-                        })),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[state.m.player1, state.m.player2],
+                    Ok(Command::Picked(AnimalPicked {
+                        animal_id,
+                        player_id: state.current_turn,
+                    })),
+                );
 
                 if query.iter().count() % 2 == 0 {
                     state.next_turn();
                 }
 
-                let now = Utc::now();
-                if query.iter().count() == PICK_COUNT - 1 {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::SetState(SetBattleState {
-                                state: BattleState::PlacementStage.into(),
-                            })),
-                        })
-                        .ok();
+                let now = clock.now();
+                if query.iter().count() == rules.pick_count() - 1 {
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::SetState(SetBattleState {
+                            state: BattleState::PlacementStage.into(),
+                        })),
+                    );
                     state.state = BattleState::PlacementStage;
                     state.deadline = DateTime::<Utc>::from_utc(
-                        NaiveDateTime::from_timestamp_opt(now.timestamp() + PLACE_TIME as i64, 0)
-                            .unwrap(),
+                        NaiveDateTime::from_timestamp_opt(
+                            now.timestamp() + rules.place_time as i64,
+                            0,
+                        )
+                        .unwrap(),
                         Utc,
                     );
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::TurnToPick(TurnToPick {
-                                player_id: None,
-                                deadline: Some(Timestamp {
-                                    seconds: state.deadline.timestamp(),
-                                    nanos: 0,
-                                }),
-                            })),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::TurnToPick(TurnToPick {
+                            player_id: None,
+                            deadline: Some(Timestamp {
+                                seconds: state.deadline.timestamp(),
+                                nanos: 0,
+                            }),
+                        })),
+                    );
                 } else {
                     state.deadline = DateTime::<Utc>::from_utc(
-                        NaiveDateTime::from_timestamp_opt(now.timestamp() + PICK_TIME as i64, 0)
-                            .unwrap(),
+                        NaiveDateTime::from_timestamp_opt(
+                            now.timestamp() + rules.pick_time as i64,
+                            0,
+                        )
+                        .unwrap(),
                         Utc,
                     );
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::TurnToPick(TurnToPick {
-                                player_id: Some(state.current_turn),
-                                deadline: Some(Timestamp {
-                                    seconds: state.deadline.timestamp(),
-                                    nanos: 0,
-                                }),
-                            })),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::TurnToPick(TurnToPick {
+                            player_id: Some(state.current_turn),
+                            deadline: Some(Timestamp {
+                                seconds: state.deadline.timestamp(),
+                                nanos: 0,
+                            }),
+                        })),
+                    );
                 }
             } else {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![player_id],
-                        res: Err(Status::not_found("Animal is not available to pick")),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[player_id],
+                    Err(Status::not_found("Animal is not available to pick")),
+                );
             }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn place(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
     mut state: ResMut<GameState>,
     mut commands: Commands,
     query: Query<(Entity, &AnimalId), Without<Position>>,
@@ -866,46 +2382,45 @@ fn place(
                     position: f.position.as_ref().map(|g| battle::Position {
                         x: g.x,
                         y: if state.m.player2 == *player_id {
-                            23 - g.y
+                            state.m.map.flip(g.y)
                         } else {
                             g.y
                         },
                     }),
                 })
                 .collect();
-            if (filtered_query.count() != PICK_COUNT / 2 || animals.len() != PICK_COUNT / 2)
-                && animals.iter().all(|f| {
-                    f.position.is_some()
-                        && (0..7).contains(&f.position.as_ref().unwrap().x)
-                        && (0..24).contains(&f.position.as_ref().unwrap().y)
-                })
+            //Every animal of the player goes to a free square of their half
+            let rows = state.m.map.zone(state.m.player2 == *player_id);
+            let valid = filtered_query.count() == rules.team_size
+                && animals.len() == rules.team_size
+                && all_unique_elements(animals.iter().map(|f| f.animal_id))
                 && animals.iter().all(|f| {
-                    query
-                        .iter()
-                        .filter(|(_, animal_id)| animal_id.player_id == *player_id)
-                        .any(|(_, id)| id.id == f.animal_id)
-                        && all_unique_elements(animals.iter().map(|f| f.animal_id))
-                        && all_unique_elements(animals.iter().map(|f| {
-                            (
-                                f.position.as_ref().unwrap().x,
-                                f.position.as_ref().unwrap().y,
-                            )
-                        }))
-                        && objects.iter().all(|g| {
-                            !(g.x == f.position.as_ref().unwrap().x
-                                && g.y == f.position.as_ref().unwrap().y)
-                        })
+                    let Some(position) = &f.position else {
+                        return false;
+                    };
+                    state.m.map.on_board(position.x, position.y)
+                        && rows.contains(&position.y)
+                        && query
+                            .iter()
+                            .filter(|(_, animal_id)| animal_id.player_id == *player_id)
+                            .any(|(_, id)| id.id == f.animal_id)
+                        && objects
+                            .iter()
+                            .all(|g| !(g.x == position.x && g.y == position.y))
                 })
-            {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![*player_id],
-                        res: Err(Status::permission_denied("Not all animals position send")),
-                    })
-                    .ok();
+                && all_unique_elements(animals.iter().map(|f| {
+                    (
+                        f.position.as_ref().unwrap().x,
+                        f.position.as_ref().unwrap().y,
+                    )
+                }));
+            if !valid {
+                state.tx.send(
+                    &[*player_id],
+                    Err(Status::permission_denied("Not all animals position send")),
+                );
             } else {
-                let mut vec = Vec::with_capacity(PICK_COUNT / 2);
+                let mut vec = Vec::with_capacity(rules.team_size);
                 for (entity, animal_id) in query
                     .iter()
                     .filter(|(_, animal_id)| animal_id.player_id == *player_id)
@@ -939,51 +2454,49 @@ fn place(
                     });
                 }
 
-                if already_set.iter().count() == PICK_COUNT / 2 {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::Placed(AnimalsPlaced { animals: vec })),
-                        })
-                        .ok();
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::SetState(SetBattleState {
-                                state: BattleState::GameStage.into(),
-                            })),
-                        })
-                        .ok();
+                if already_set.iter().count() == rules.team_size {
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::Placed(AnimalsPlaced { animals: vec })),
+                    );
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::SetState(SetBattleState {
+                            state: BattleState::GameStage.into(),
+                        })),
+                    );
                     state.state = BattleState::GameStage;
 
-                    let now = Utc::now();
+                    let now = clock.now();
                     state.deadline = DateTime::<Utc>::from_utc(
-                        NaiveDateTime::from_timestamp_opt(now.timestamp() + TURN_TIME as i64, 0)
-                            .unwrap(),
+                        NaiveDateTime::from_timestamp_opt(
+                            now.timestamp() + rules.turn_time as i64,
+                            0,
+                        )
+                        .unwrap(),
                         Utc,
                     );
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::TurnToPick(TurnToPick {
-                                player_id: Some(state.current_turn),
-                                deadline: Some(Timestamp {
-                                    seconds: state.deadline.timestamp(),
-                                    nanos: 0,
-                                }),
-                            })),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[state.m.player1, state.m.player2],
+                        Ok(Command::TurnToPick(TurnToPick {
+                            player_id: Some(state.current_turn),
+                            deadline: Some(Timestamp {
+                                seconds: state.deadline.timestamp(),
+                                nanos: 0,
+                            }),
+                        })),
+                    );
                 }
             }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn place_timeout(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
+    mut rng: ResMut<BattleRng>,
     mut state: ResMut<GameState>,
     mut commands: Commands,
     query: Query<(Entity, &AnimalId), Without<Position>>,
@@ -994,19 +2507,12 @@ fn place_timeout(
         return;
     }
 
-    let rng = &mut rand::thread_rng();
-
-    let mut vec = Vec::with_capacity(PICK_COUNT / 2);
+    let mut vec = Vec::with_capacity(rules.team_size);
 
     for player_id in [state.m.player1, state.m.player2] {
         let mut positions = Vec::new();
-        for x in 0..7 {
-            let bound = if player_id == state.m.player2 {
-                12..24
-            } else {
-                0..12
-            };
-            for y in bound {
+        for x in 0..state.m.map.width {
+            for y in state.m.map.zone(player_id == state.m.player2) {
                 if !objects.iter().any(|f| f.x == x && f.y == y) {
                     positions.push(Position { x, y });
                 }
@@ -1018,9 +2524,9 @@ fn place_timeout(
             .filter(|(_, animal_id)| animal_id.player_id == player_id)
             .collect();
 
-        let now = Utc::now();
-        let random_positions = positions.choose_multiple(rng, PICK_COUNT / 2);
+        let now = clock.now();
         if !filtered_query.is_empty() && (state.deadline - now).num_milliseconds() <= 0 {
+            let random_positions = positions.choose_multiple(&mut rng.0, rules.team_size);
             for ((entity, animal_id), position) in filtered_query.iter().zip(random_positions) {
                 vec.push(AnimalPlaced {
                     player_id,
@@ -1049,49 +2555,40 @@ fn place_timeout(
                 })
                 .collect::<Vec<AnimalPlaced>>(),
         );
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::Placed(AnimalsPlaced { animals: vec })),
-            })
-            .ok();
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::SetState(SetBattleState {
-                    state: BattleState::GameStage.into(),
-                })),
-            })
-            .ok();
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::Placed(AnimalsPlaced { animals: vec })),
+        );
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::SetState(SetBattleState {
+                state: BattleState::GameStage.into(),
+            })),
+        );
         state.state = BattleState::GameStage;
 
-        let now = Utc::now();
+        let now = clock.now();
         state.deadline = DateTime::<Utc>::from_utc(
-            NaiveDateTime::from_timestamp_opt(now.timestamp() + TURN_TIME as i64, 0).unwrap(),
+            NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.turn_time as i64, 0).unwrap(),
             Utc,
         );
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::TurnToPick(TurnToPick {
-                    player_id: Some(state.current_turn),
-                    deadline: Some(Timestamp {
-                        seconds: state.deadline.timestamp(),
-                        nanos: 0,
-                    }),
-                })),
-            })
-            .ok();
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::TurnToPick(TurnToPick {
+                player_id: Some(state.current_turn),
+                deadline: Some(Timestamp {
+                    seconds: state.deadline.timestamp(),
+                    nanos: 0,
+                }),
+            })),
+        );
     }
 }
 
 fn use_animal(
     state: Res<GameState>,
     mut commands: Commands,
-    not_used: Query<(Entity, &AnimalId), (With<Position>, Without<Used>)>,
+    not_used: Query<(Entity, &AnimalId, &Effects), (With<Position>, Without<Used>)>,
     used: Query<&AnimalId, With<Used>>,
     mut event_reader: EventReader<Event>,
 ) {
@@ -1102,49 +2599,58 @@ fn use_animal(
         if let BattleMessage::UsePlayerAnimal { player_id, animal } = &my_event.message {
             if state.current_turn == *player_id {
                 if used.iter().filter(|f| f.player_id == *player_id).count() == 0 {
-                    let Some((entity, ..)) = not_used.iter().find(|(.. , f)| f.id == animal.animal_id && f.player_id == *player_id) else {
+                    let Some((entity, _, effects)) = not_used
+                        .iter()
+                        .find(|(_, f, _)| f.id == animal.animal_id && f.player_id == *player_id)
+                    else {
                         state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![*player_id],
-                            res: Err(Status::not_found(
-                                "Animal not found",
-                            )),
-                        })
-                        .ok();
+                            .tx
+                            .send(&[*player_id], Err(Status::not_found("Animal not found")));
                         return;
                     };
+                    if let Err(status) = effects.check_action() {
+                        state.tx.send(&[*player_id], Err(status));
+                        return;
+                    }
 
                     commands.entity(entity).insert(Used);
                 } else {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![*player_id],
-                            res: Err(Status::permission_denied(
-                                "One of your animals is already in use",
-                            )),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[*player_id],
+                        Err(Status::permission_denied(
+                            "One of your animals is already in use",
+                        )),
+                    );
                 }
             } else {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![*player_id],
-                        res: Err(Status::permission_denied("Not your turn")),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[*player_id],
+                    Err(Status::permission_denied("Not your turn")),
+                );
             }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn move_animal(
     state: Res<GameState>,
     mut event_reader: EventReader<Event>,
-    mut used: Query<(&AnimalId, &mut Position, &mut Mobility), With<Used>>,
-    objects: Query<&Position, Without<Used>>,
+    mut commands: Commands,
+    mut used: Query<
+        (
+            &AnimalId,
+            &mut Position,
+            &mut Mobility,
+            &mut Health,
+            &Effects,
+            &mut ActionPoints,
+        ),
+        With<Used>,
+    >,
+    objects: Query<(&Position, Option<&Egg>), Without<Used>>,
+    traps: Query<(Entity, &Trap)>,
+    watchers: Query<(&AnimalId, &Effects), Without<Used>>,
 ) {
     if state.state != BattleState::GameStage {
         return;
@@ -1158,20 +2664,55 @@ fn move_animal(
         } = my_event.message.clone()
         {
             if state.current_turn == player_id {
-                let Some((&AnimalId {player_id: _, id: animal_id}, mut position, mut mobility)) = used.iter_mut().find(|(f, ..)| f.player_id == player_id) else {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![player_id],
-                            res: Err(Status::permission_denied("Not using any animal")),
-                        })
-                        .ok();
+                let Some((
+                    &AnimalId {
+                        player_id: _,
+                        id: animal_id,
+                    },
+                    mut position,
+                    mut mobility,
+                    mut health,
+                    effects,
+                    mut ap,
+                )) = used.iter_mut().find(|(f, ..)| f.player_id == player_id)
+                else {
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Not using any animal")),
+                    );
                     return;
                 };
+                if let Err(status) = effects.check_action() {
+                    state.tx.send(&[player_id], Err(status));
+                    return;
+                }
                 if state.m.player2 != player_id {
-                    pos.y = 23 - pos.y;
+                    pos.y = state.m.map.flip(pos.y);
+                }
+                if !state.m.map.on_board(pos.x, pos.y) {
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Cannot move here")),
+                    );
+                    return;
                 }
 
+                //Allies can walk through their eggs, but nobody can stop on them
+                let passable = |x: i32, y: i32| {
+                    !objects.iter().any(|(f, egg)| {
+                        f.x == x
+                            && f.y == y
+                            && (!matches!(egg, Some(egg) if egg.player_id == player_id)
+                                || (x == pos.x && y == pos.y))
+                    })
+                };
+                let bunny_hop = state
+                    .animals
+                    .get(animal_id)
+                    .unwrap()
+                    .passive("Bunny Hop")
+                    .is_some();
+                let mut jumped = false;
                 let mut squares = 0;
                 if position.x == pos.x {
                     for iy in if pos.y > position.y {
@@ -1179,14 +2720,16 @@ fn move_animal(
                     } else {
                         pos.y..=(position.y - 1)
                     } {
-                        if objects.iter().any(|f| f.x == pos.x && f.y == iy) {
-                            state
-                                .tx
-                                .send(BattleMessage::Response {
-                                    receivers: vec![player_id],
-                                    res: Err(Status::permission_denied("Cannot move here")),
-                                })
-                                .ok();
+                        if !passable(pos.x, iy) {
+                            if bunny_hop && !jumped && iy != pos.y {
+                                jumped = true;
+                                squares += 1;
+                                continue;
+                            }
+                            state.tx.send(
+                                &[player_id],
+                                Err(Status::permission_denied("Cannot move here")),
+                            );
                             return;
                         }
                         squares += 1;
@@ -1197,14 +2740,16 @@ fn move_animal(
                     } else {
                         pos.x..=(position.x - 1)
                     } {
-                        if objects.iter().any(|f| f.x == ix && f.y == pos.y) {
-                            state
-                                .tx
-                                .send(BattleMessage::Response {
-                                    receivers: vec![player_id],
-                                    res: Err(Status::permission_denied("Cannot move here")),
-                                })
-                                .ok();
+                        if !passable(ix, pos.y) {
+                            if bunny_hop && !jumped && ix != pos.x {
+                                jumped = true;
+                                squares += 1;
+                                continue;
+                            }
+                            state.tx.send(
+                                &[player_id],
+                                Err(Status::permission_denied("Cannot move here")),
+                            );
                             return;
                         }
                         squares += 1;
@@ -1212,96 +2757,157 @@ fn move_animal(
                 }
 
                 if mobility.squares >= squares && (pos.x == position.x || pos.y == position.y) {
+                    let cost = squares as f32 * MOVE_AP_COST;
+                    if ap.amount < cost {
+                        state.tx.send(
+                            &[player_id],
+                            Err(Status::permission_denied("Not enough action points")),
+                        );
+                        return;
+                    }
+                    ap.amount -= cost;
                     mobility.squares -= squares;
                     position.x = pos.x;
                     position.y = pos.y;
 
-                    for rec in [state.m.player1, state.m.player2] {
-                        state
-                            .tx
-                            .send(BattleMessage::Response {
-                                receivers: vec![rec],
-                                res: Ok(Command::Moved(AnimalMoved {
-                                    player_id,
-                                    position: Some(battle::Position { x: pos.x, y: pos.y }),
-                                    animal_id,
-                                    squares: if rec == player_id {
-                                        Some(squares)
-                                    } else {
-                                        None
-                                    },
-                                })),
-                            })
-                            .ok();
+                    //Invisible animals can be seen with Night life or Cat's Eye only
+                    let visible = !effects.has(EffectType::Invisible)
+                        || watchers.iter().any(|(f, e)| {
+                            f.player_id != player_id
+                                && (e.has(EffectType::TrueSight)
+                                    || state
+                                        .animals
+                                        .get(f.id)
+                                        .unwrap()
+                                        .passive("Night life")
+                                        .is_some())
+                        });
+                    for rec in [state.m.player1, state.m.player2]
+                        .into_iter()
+                        .filter(|&f| f == player_id || visible)
+                    {
+                        state.tx.send(
+                            &[rec],
+                            Ok(Command::Moved(AnimalMoved {
+                                player_id,
+                                position: Some(battle::Position { x: pos.x, y: pos.y }),
+                                animal_id,
+                                squares: if rec == player_id {
+                                    Some(squares)
+                                } else {
+                                    None
+                                },
+                                action_points: if rec == player_id {
+                                    Some(ap.amount)
+                                } else {
+                                    None
+                                },
+                            })),
+                        );
+                    }
+
+                    if let Some((entity, trap)) = traps
+                        .iter()
+                        .find(|(_, f)| f.player_id != player_id && f.x == pos.x && f.y == pos.y)
+                    {
+                        commands.entity(entity).despawn();
+                        let damage = health.take_damage(TRAP_DAMAGE);
+                        state.tx.send(
+                            &[state.m.player1, state.m.player2],
+                            Ok(Command::Damaged(AnimalDamaged {
+                                player_id: trap.player_id,
+                                damaged_animal_id: animal_id,
+                                damager_animal_id: trap.animal_id,
+                                damage,
+                                action_points: None,
+                            })),
+                        );
+                        state.tx.send(
+                            &[trap.player_id],
+                            Ok(Command::ObjectRemoved(ObjectRemoved {
+                                position: Some(battle::Position { x: pos.x, y: pos.y }),
+                            })),
+                        );
                     }
                 } else {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![player_id],
-                            res: Err(Status::permission_denied("Not enough squares to move")),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Not enough squares to move")),
+                    );
                 }
             } else {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![player_id],
-                        res: Err(Status::permission_denied("Not your turn")),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[player_id],
+                    Err(Status::permission_denied("Not your turn")),
+                );
             }
         }
     }
 }
 
 fn turn_timeout(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
     mut state: ResMut<GameState>,
     mut commands: Commands,
-    mut used: Query<(Entity, &AnimalId, &mut Mobility), With<Used>>,
+    mut animals: Query<(
+        Entity,
+        &AnimalId,
+        Option<&Used>,
+        &mut Mobility,
+        &mut Cooldowns,
+        &mut Effects,
+        &mut Health,
+        &mut HitDamage,
+        &mut HitDamageBlock,
+        &mut ActionPoints,
+        &APRecovery,
+    )>,
 ) {
     if state.state != BattleState::GameStage {
         return;
     }
-    let now = Utc::now();
+    let now = clock.now();
     if (state.deadline - now).num_milliseconds() <= 0 {
-        for (entity, AnimalId { id: animal_id, .. }, mut mobility) in used.iter_mut() {
-            let animal = state
-                .animals
-                .animals
-                .iter()
-                .find(|f| f.id == *animal_id)
-                .unwrap();
-            mobility.squares = animal.mobility;
-            commands.entity(entity).remove::<Used>().remove::<Hit>();
-        }
+        finish_turn(&state, state.current_turn, &mut commands, &mut animals);
         state.deadline = DateTime::<Utc>::from_utc(
-            NaiveDateTime::from_timestamp_opt(now.timestamp() + TURN_TIME as i64, 0).unwrap(),
+            NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.turn_time as i64, 0).unwrap(),
             Utc,
         );
         state.next_turn();
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::TurnToPick(TurnToPick {
-                    player_id: Some(state.current_turn),
-                    deadline: Some(Timestamp {
-                        seconds: state.deadline.timestamp(),
-                        nanos: 0,
-                    }),
-                })),
-            })
-            .ok();
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::TurnToPick(TurnToPick {
+                player_id: Some(state.current_turn),
+                deadline: Some(Timestamp {
+                    seconds: state.deadline.timestamp(),
+                    nanos: 0,
+                }),
+            })),
+        );
+        restore_action_points(&state, &mut animals);
     }
 }
 
 fn end_turn(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
     mut state: ResMut<GameState>,
     mut event_reader: EventReader<Event>,
     mut commands: Commands,
-    mut used: Query<(Entity, &AnimalId, &mut Mobility), With<Used>>,
+    mut animals: Query<(
+        Entity,
+        &AnimalId,
+        Option<&Used>,
+        &mut Mobility,
+        &mut Cooldowns,
+        &mut Effects,
+        &mut Health,
+        &mut HitDamage,
+        &mut HitDamageBlock,
+        &mut ActionPoints,
+        &APRecovery,
+    )>,
 ) {
     if state.state != BattleState::GameStage {
         return;
@@ -1309,55 +2915,185 @@ fn end_turn(
     for my_event in event_reader.iter() {
         if let BattleMessage::EndTurn { player_id } = my_event.message {
             if state.current_turn == player_id {
-                let now = Utc::now();
-                for (entity, AnimalId { id: animal_id, .. }, mut mobility) in used.iter_mut() {
-                    let animal = state
-                        .animals
-                        .animals
-                        .iter()
-                        .find(|f| f.id == *animal_id)
-                        .unwrap();
-                    mobility.squares = animal.mobility;
-                    commands.entity(entity).remove::<Used>().remove::<Hit>();
-                }
+                let now = clock.now();
+                finish_turn(&state, player_id, &mut commands, &mut animals);
                 state.deadline = DateTime::<Utc>::from_utc(
-                    NaiveDateTime::from_timestamp_opt(now.timestamp() + TURN_TIME as i64, 0)
+                    NaiveDateTime::from_timestamp_opt(now.timestamp() + rules.turn_time as i64, 0)
                         .unwrap(),
                     Utc,
                 );
                 state.next_turn();
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![state.m.player1, state.m.player2],
-                        res: Ok(Command::TurnToPick(TurnToPick {
-                            player_id: Some(state.current_turn),
-                            deadline: Some(Timestamp {
-                                seconds: state.deadline.timestamp(),
-                                nanos: 0,
-                            }),
-                        })),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[state.m.player1, state.m.player2],
+                    Ok(Command::TurnToPick(TurnToPick {
+                        player_id: Some(state.current_turn),
+                        deadline: Some(Timestamp {
+                            seconds: state.deadline.timestamp(),
+                            nanos: 0,
+                        }),
+                    })),
+                );
+                restore_action_points(&state, &mut animals);
             } else {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![player_id],
-                        res: Err(Status::permission_denied("Not your turn")),
-                    })
-                    .ok();
+                state.tx.send(
+                    &[player_id],
+                    Err(Status::permission_denied("Not your turn")),
+                );
             }
         }
     }
 }
 
+//Ticks cooldowns and effects of the player animals, and restores their steps
+fn finish_turn(
+    state: &GameState,
+    player_id: i32,
+    commands: &mut Commands,
+    animals: &mut Query<(
+        Entity,
+        &AnimalId,
+        Option<&Used>,
+        &mut Mobility,
+        &mut Cooldowns,
+        &mut Effects,
+        &mut Health,
+        &mut HitDamage,
+        &mut HitDamageBlock,
+        &mut ActionPoints,
+        &APRecovery,
+    )>,
+) {
+    for (
+        entity,
+        animal_id,
+        used,
+        mut mobility,
+        mut cooldowns,
+        mut effects,
+        mut health,
+        mut damage,
+        mut block,
+        _,
+        _,
+    ) in animals
+        .iter_mut()
+        .filter(|(_, f, ..)| f.player_id == player_id)
+    {
+        let animal = state.animals.get(animal_id.id).unwrap();
+        cooldowns.tick();
+        let invulnerable = effects.has(EffectType::Invulnerable);
+        for effect in effects.active.iter_mut() {
+            if !matches!(effect.effect, EffectType::Bleed | EffectType::Mud) || invulnerable {
+                continue;
+            }
+            let amount =
+                if effect.effect == EffectType::Bleed && animal.passive("Thick Skin").is_some() {
+                    effect.value * (100 - THICK_SKIN_PERCENTS) / 100
+                } else {
+                    effect.value
+                };
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::Damaged(AnimalDamaged {
+                    player_id: effect.source.0,
+                    damaged_animal_id: animal_id.id,
+                    damager_animal_id: effect.source.1,
+                    damage: health.take_damage(amount),
+                    action_points: None,
+                })),
+            );
+        }
+        for effect in effects.active.iter_mut() {
+            effect.turns -= 1;
+            if effect.turns > 0 {
+                continue;
+            }
+            match effect.effect {
+                EffectType::DamageBoost => damage.amount -= effect.delta,
+                EffectType::Weakness => damage.amount += effect.delta,
+                EffectType::Shield | EffectType::Invulnerable => {
+                    block.percents -= effect.delta as f32
+                }
+                _ => {}
+            }
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::EffectExpired(EffectExpired {
+                    animal_id: animal_id.id,
+                    effect: effect.effect.into(),
+                })),
+            );
+        }
+        effects.active.retain(|f| f.turns > 0);
+        mobility.squares = (animal.mobility + effects.mobility_bonus()).max(0);
+        if used.is_some() {
+            commands.entity(entity).remove::<Used>().remove::<Hit>();
+        }
+    }
+}
+
+//Regenerates action points of the player, whose turn starts
+fn restore_action_points(
+    state: &GameState,
+    animals: &mut Query<(
+        Entity,
+        &AnimalId,
+        Option<&Used>,
+        &mut Mobility,
+        &mut Cooldowns,
+        &mut Effects,
+        &mut Health,
+        &mut HitDamage,
+        &mut HitDamageBlock,
+        &mut ActionPoints,
+        &APRecovery,
+    )>,
+) {
+    for (_, animal_id, .., mut ap, recovery) in animals
+        .iter_mut()
+        .filter(|(_, f, ..)| f.player_id == state.current_turn)
+    {
+        let max = state.animals.get(animal_id.id).unwrap().action_points as f32;
+        ap.amount = (ap.amount + recovery.amount).min(max);
+        state.tx.send(
+            &[animal_id.player_id],
+            Ok(Command::ActionPoints(ActionPointsUpdated {
+                animal_id: animal_id.id,
+                action_points: ap.amount,
+            })),
+        );
+    }
+}
+
 fn damage(
     state: Res<GameState>,
     mut event_reader: EventReader<Event>,
     mut commands: Commands,
-    used: Query<(&AnimalId, Entity, &Position, &HitDamage), (With<Used>, Without<Hit>)>,
-    mut animals: Query<(&AnimalId, &Position, &mut Health, &HitDamageBlock), Without<Used>>,
+    mut used: Query<
+        (
+            &AnimalId,
+            Entity,
+            &Position,
+            &HitDamage,
+            &mut Health,
+            &mut Effects,
+            &mut HitStreak,
+            &mut ActionPoints,
+        ),
+        (With<Used>, Without<Hit>),
+    >,
+    mut animals: Query<
+        (
+            &AnimalId,
+            &Position,
+            &mut Health,
+            &HitDamageBlock,
+            &mut HitsTaken,
+            &mut Effects,
+        ),
+        Without<Used>,
+    >,
+    eggs: Query<(Entity, &Egg, &Position)>,
 ) {
     if state.state != BattleState::GameStage {
         return;
@@ -1371,91 +3107,942 @@ fn damage(
         } = my_event.message.clone()
         {
             if state.current_turn == player_id {
-                let Some((&AnimalId {player_id: _, id: animal_id}, entity, position, hit_damage)) = used.iter().find(|(f, ..)| f.player_id == player_id) else {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![player_id],
-                            res: Err(Status::permission_denied("Not using any animal")),
-                        })
-                        .ok();
+                let Some((
+                    &AnimalId {
+                        player_id: _,
+                        id: animal_id,
+                    },
+                    entity,
+                    position,
+                    hit_damage,
+                    mut health,
+                    mut effects,
+                    mut streak,
+                    mut ap,
+                )) = used.iter_mut().find(|(f, ..)| f.player_id == player_id)
+                else {
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Not using any animal")),
+                    );
                     return;
                 };
+                if let Err(status) = effects.check_attack() {
+                    state.tx.send(&[player_id], Err(status));
+                    return;
+                }
+                if ap.amount < ATTACK_AP_COST {
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Not enough action points")),
+                    );
+                    return;
+                }
                 let mut pos = Position { x: pos.x, y: pos.y };
                 if state.m.player2 != player_id {
-                    pos.y = 23 - pos.y;
+                    pos.y = state.m.map.flip(pos.y);
                 }
                 if position.can_hit(&pos) {
-                    let Some(mut val) = animals.iter_mut().find(|(f, p, ..)| f.player_id != player_id && p.x == pos.x && p.y == pos.y) else {
-                        state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![player_id],
-                            res: Err(Status::permission_denied("Nobody to hit")),
-                        })
-                        .ok();
-                    return;
+                    if effects.reveal() {
+                        state.tx.send(
+                            &[state.m.player1, state.m.player2],
+                            Ok(Command::EffectExpired(EffectExpired {
+                                animal_id,
+                                effect: EffectType::Invisible.into(),
+                            })),
+                        );
+                    }
+                    if let Some((egg_entity, egg, _)) = eggs
+                        .iter()
+                        .find(|(_, f, p)| f.player_id != player_id && p.x == pos.x && p.y == pos.y)
+                    {
+                        //Breaking an egg hurts the attacker
+                        commands.entity(egg_entity).despawn();
+                        commands.entity(entity).insert(Hit);
+                        ap.amount -= ATTACK_AP_COST;
+                        let damage = health.take_damage(EGG_DAMAGE);
+                        effects.add(
+                            EffectType::Stun,
+                            EGG_STUN,
+                            &AnimalId {
+                                player_id: egg.player_id,
+                                id: egg.animal_id,
+                            },
+                        );
+                        state.tx.send(
+                            &[state.m.player1, state.m.player2],
+                            Ok(Command::ObjectRemoved(ObjectRemoved {
+                                position: Some(battle::Position { x: pos.x, y: pos.y }),
+                            })),
+                        );
+                        state.tx.send(
+                            &[state.m.player1, state.m.player2],
+                            Ok(Command::Damaged(AnimalDamaged {
+                                player_id: egg.player_id,
+                                damaged_animal_id: animal_id,
+                                damager_animal_id: egg.animal_id,
+                                damage,
+                                action_points: None,
+                            })),
+                        );
+                        state.tx.send(
+                            &[player_id],
+                            Ok(Command::ActionPoints(ActionPointsUpdated {
+                                animal_id,
+                                action_points: ap.amount,
+                            })),
+                        );
+                        continue;
+                    }
+                    let Some(mut val) = animals.iter_mut().find(|(f, p, ..)| {
+                        f.player_id != player_id && p.x == pos.x && p.y == pos.y
+                    }) else {
+                        state.tx.send(
+                            &[player_id],
+                            Err(Status::permission_denied("Nobody to hit")),
+                        );
+                        return;
                     };
 
-                    let damage = val.2.take_damage(
-                        ((1f32 - val.3.percents / 100f32) * hit_damage.amount as f32) as i32,
-                    );
+                    //Sly dodges every third hit
+                    val.4.count += 1;
+                    let sly = state.animals.get(val.0.id).unwrap().passive("Sly");
+                    let damage = if sly.is_some() && val.4.count % SLY_HIT_PERIOD == 0 {
+                        0
+                    } else {
+                        val.2.take_damage(val.3.reduce(hit_damage.amount))
+                    };
+                    //Persistance makes every third hit on the same animal bleed
+                    if streak.target != val.0.id {
+                        streak.target = val.0.id;
+                        streak.count = 0;
+                    }
+                    streak.count += 1;
+                    let persistance = state.animals.get(animal_id).unwrap().passive("Persistance");
+                    if persistance.is_some() && streak.count % PERSISTANCE_HIT_PERIOD == 0 {
+                        let source = AnimalId {
+                            player_id,
+                            id: animal_id,
+                        };
+                        val.5.add(EffectType::Bleed, PERSISTANCE_BLEED, &source);
+                    }
                     commands.entity(entity).insert(Hit);
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![state.m.player1, state.m.player2],
-                            res: Ok(Command::Damaged(AnimalDamaged {
+                    ap.amount -= ATTACK_AP_COST;
+                    for rec in [state.m.player1, state.m.player2] {
+                        state.tx.send(
+                            &[rec],
+                            Ok(Command::Damaged(AnimalDamaged {
                                 player_id,
                                 damaged_animal_id: val.0.id,
                                 damager_animal_id: animal_id,
                                 damage,
+                                action_points: if rec == player_id {
+                                    Some(ap.amount)
+                                } else {
+                                    None
+                                },
                             })),
-                        })
-                        .ok();
+                        );
+                    }
                 } else {
-                    state
-                        .tx
-                        .send(BattleMessage::Response {
-                            receivers: vec![player_id],
-                            res: Err(Status::permission_denied("Cannot hit in this position")),
-                        })
-                        .ok();
+                    state.tx.send(
+                        &[player_id],
+                        Err(Status::permission_denied("Cannot hit in this position")),
+                    );
                 }
             } else {
-                state
-                    .tx
-                    .send(BattleMessage::Response {
-                        receivers: vec![player_id],
-                        res: Err(Status::permission_denied("Not your turn")),
+                state.tx.send(
+                    &[player_id],
+                    Err(Status::permission_denied("Not your turn")),
+                );
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn use_ability(
+    state: Res<GameState>,
+    mut event_reader: EventReader<Event>,
+    mut commands: Commands,
+    mut used: Query<
+        (
+            &AnimalId,
+            &Position,
+            &HitDamage,
+            &mut Health,
+            &mut ActionPoints,
+            &mut Cooldowns,
+            &mut Effects,
+        ),
+        With<Used>,
+    >,
+    mut animals: Query<
+        (
+            &AnimalId,
+            &mut Position,
+            &mut Health,
+            &HitDamageBlock,
+            &mut ActionPoints,
+            &mut Effects,
+        ),
+        Without<Used>,
+    >,
+    objects: Query<&Position, Without<AnimalId>>,
+    traps: Query<&Trap>,
+) {
+    if state.state != BattleState::GameStage {
+        return;
+    }
+    for my_event in event_reader.iter() {
+        if let BattleMessage::UsePlayerAbility { player_id, ability } = &my_event.message {
+            let responses = cast_ability(
+                &state,
+                *player_id,
+                ability,
+                &mut commands,
+                &mut used,
+                &mut animals,
+                &objects,
+                &traps,
+            );
+            match responses {
+                Ok(responses) => {
+                    for (receivers, command) in responses {
+                        state.tx.send(&receivers, Ok(command));
+                    }
+                }
+                Err(status) => {
+                    state.tx.send(&[*player_id], Err(status));
+                }
+            }
+        }
+    }
+}
+
+//Maximum distance to the target, None means the whole map
+fn ability_range(name: &str) -> Option<i32> {
+    match name {
+        "Pounce" => Some(POUNCE_DISTANCE),
+        "Mouse hole" | "Cheese lure" | "Mud Patch" => None,
+        _ => Some(1),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn cast_ability(
+    state: &GameState,
+    player_id: i32,
+    cmd: &UseAbility,
+    commands: &mut Commands,
+    used: &mut Query<
+        (
+            &AnimalId,
+            &Position,
+            &HitDamage,
+            &mut Health,
+            &mut ActionPoints,
+            &mut Cooldowns,
+            &mut Effects,
+        ),
+        With<Used>,
+    >,
+    animals: &mut Query<
+        (
+            &AnimalId,
+            &mut Position,
+            &mut Health,
+            &HitDamageBlock,
+            &mut ActionPoints,
+            &mut Effects,
+        ),
+        Without<Used>,
+    >,
+    objects: &Query<&Position, Without<AnimalId>>,
+    traps: &Query<&Trap>,
+) -> Result<Vec<(Vec<i32>, Command)>, Status> {
+    if state.current_turn != player_id {
+        return Err(Status::permission_denied("Not your turn"));
+    }
+    let (caster, position, hit_damage, mut health, mut ap, mut cooldowns, mut effects) = used
+        .iter_mut()
+        .find(|(f, ..)| f.player_id == player_id)
+        .ok_or_else(|| Status::permission_denied("Not using any animal"))?;
+    let animal = state.animals.get(caster.id).unwrap();
+    let ability = animal
+        .active(&cmd.ability)
+        .ok_or_else(|| Status::not_found("Ability not found"))?;
+    let attack =
+        matches!(ability.target, Some(AbilityTarget::Enemy)) || ability.name == "Mouse hole";
+    if attack {
+        effects.check_attack()?;
+    } else {
+        effects.check_action()?;
+    }
+    if !cooldowns.is_ready(&ability.name) {
+        return Err(Status::permission_denied("Ability is on cooldown"));
+    }
+    let cost = ability.cost.unwrap_or(0) as f32;
+    if ap.amount < cost {
+        return Err(Status::permission_denied("Not enough action points"));
+    }
+
+    let target = cmd.position.as_ref().map(|g| Position {
+        x: g.x,
+        y: if state.m.player2 != player_id {
+            state.m.map.flip(g.y)
+        } else {
+            g.y
+        },
+    });
+    let mut occupied: Vec<Position> = objects.iter().cloned().collect();
+    occupied.extend(animals.iter().map(|(_, p, ..)| p.clone()));
+    occupied.push(position.clone());
+    let is_free = |p: &Position| {
+        p.on_board(&state.m.map) && !occupied.iter().any(|f| f.x == p.x && f.y == p.y)
+    };
+
+    match ability.target {
+        Some(AbilityTarget::Enemy) | Some(AbilityTarget::EmptySquare) => {
+            let Some(target) = &target else {
+                return Err(Status::permission_denied("Ability needs a target"));
+            };
+            if ability_range(&ability.name).is_some_and(|range| position.distance(target) > range) {
+                return Err(Status::permission_denied("Target is too far"));
+            }
+            if matches!(ability.target, Some(AbilityTarget::Enemy))
+                && !animals.iter().any(|(f, p, ..)| {
+                    f.player_id != player_id && p.x == target.x && p.y == target.y
+                })
+            {
+                return Err(Status::permission_denied("Nobody to hit"));
+            }
+            if matches!(ability.target, Some(AbilityTarget::EmptySquare))
+                && (!is_free(target)
+                    || traps
+                        .iter()
+                        .any(|f| f.player_id == player_id && f.x == target.x && f.y == target.y))
+            {
+                return Err(Status::permission_denied("Square is not empty"));
+            }
+        }
+        _ => {}
+    }
+
+    let everyone = vec![state.m.player1, state.m.player2];
+    let mut responses = Vec::new();
+    if attack && effects.reveal() {
+        responses.push((
+            everyone.clone(),
+            Command::EffectExpired(EffectExpired {
+                animal_id: caster.id,
+                effect: EffectType::Invisible.into(),
+            }),
+        ));
+    }
+    match ability.name.as_str() {
+        "Pounce" | "Scratch" | "Deep Bite" | "Gnaw" | "Charge" | "Bite" => {
+            let target = target.as_ref().unwrap();
+            let (target_id, mut target_position, mut target_health, block, _, mut target_effects) =
+                animals
+                    .iter_mut()
+                    .find(|(f, p, ..)| {
+                        f.player_id != player_id && p.x == target.x && p.y == target.y
                     })
-                    .ok();
+                    .unwrap();
+            let mut damage = match ability.name.as_str() {
+                "Pounce" => {
+                    let target_animal = state.animals.get(target_id.id).unwrap();
+                    if matches!(target_animal.name.as_str(), "Mouse" | "Chick") {
+                        block.reduce(hit_damage.amount + POUNCE_EXTRA_DAMAGE)
+                    } else {
+                        block.reduce(hit_damage.amount)
+                    }
+                }
+                "Deep Bite" => block.reduce(target_health.amount * DEEP_BITE_PERCENTS / 100),
+                "Gnaw" => block.reduce(GNAW_DAMAGE),
+                "Charge" => block.reduce(CHARGE_DAMAGE),
+                _ => block.reduce(hit_damage.amount),
+            };
+            match ability.name.as_str() {
+                "Pounce" => target_effects.add(EffectType::Stun, POUNCE_STUN, caster),
+                "Scratch" => target_effects.add(EffectType::Bleed, SCRATCH_BLEED, caster),
+                "Gnaw" => target_effects.add(EffectType::Weakness, GNAW_WEAKNESS, caster),
+                "Bite" => target_effects.add(EffectType::Slow, BITE_SLOW, caster),
+                _ => {}
+            }
+            let mut moved = None;
+            if ability.name == "Charge" {
+                //Knock the target back, or ram it into whatever stands behind
+                let behind = Position {
+                    x: 2 * target.x - position.x,
+                    y: 2 * target.y - position.y,
+                };
+                if is_free(&behind) {
+                    target_position.x = behind.x;
+                    target_position.y = behind.y;
+                    moved = Some(behind);
+                } else {
+                    damage += block.reduce(CHARGE_DAMAGE / 2);
+                }
+            }
+            responses.push((
+                everyone.clone(),
+                Command::Damaged(AnimalDamaged {
+                    player_id,
+                    damaged_animal_id: target_id.id,
+                    damager_animal_id: caster.id,
+                    damage: target_health.take_damage(damage),
+                    action_points: None,
+                }),
+            ));
+            if let Some(behind) = moved {
+                responses.push((
+                    everyone.clone(),
+                    Command::Moved(AnimalMoved {
+                        player_id: target_id.player_id,
+                        position: Some(battle::Position {
+                            x: behind.x,
+                            y: behind.y,
+                        }),
+                        animal_id: target_id.id,
+                        squares: None,
+                        action_points: None,
+                    }),
+                ));
+            }
+        }
+        "Mouse hole" => {
+            let target = target.as_ref().unwrap();
+            for (target_id, p, mut target_health, block, ..) in animals.iter_mut() {
+                if target_id.player_id != player_id
+                    && (p.x - target.x).abs() <= 1
+                    && (p.y - target.y).abs() <= 1
+                {
+                    responses.push((
+                        everyone.clone(),
+                        Command::Damaged(AnimalDamaged {
+                            player_id,
+                            damaged_animal_id: target_id.id,
+                            damager_animal_id: caster.id,
+                            damage: target_health.take_damage(block.reduce(hit_damage.amount)),
+                            action_points: None,
+                        }),
+                    ));
+                }
+            }
+        }
+        "Egg Bomb" => {
+            let target = target.as_ref().unwrap();
+            commands.spawn((
+                Egg {
+                    player_id,
+                    animal_id: caster.id,
+                },
+                target.clone(),
+            ));
+            responses.push((
+                everyone.clone(),
+                Command::ObjectPlaced(ObjectPlaced {
+                    object: Some(GameObject {
+                        png_name: Some("Egg".to_string()),
+                        x: target.x,
+                        y: target.y,
+                        object_type: GameObjectType::Solid.into(),
+                    }),
+                }),
+            ));
+        }
+        "It's a trap" => {
+            let target = target.as_ref().unwrap();
+            commands.spawn(Trap {
+                player_id,
+                animal_id: caster.id,
+                x: target.x,
+                y: target.y,
+            });
+            responses.push((
+                vec![player_id],
+                Command::ObjectPlaced(ObjectPlaced {
+                    object: Some(GameObject {
+                        png_name: Some("Trap".to_string()),
+                        x: target.x,
+                        y: target.y,
+                        object_type: GameObjectType::Walkable.into(),
+                    }),
+                }),
+            ));
+        }
+        "Burrow" => {
+            let amount =
+                (health.amount * BURROW_HEAL_PERCENTS / 100).min(animal.hp - health.amount);
+            health.amount += amount;
+            effects.add(EffectType::Invulnerable, BURROW_INVULNERABILITY, caster);
+            responses.push((
+                everyone.clone(),
+                Command::Healed(AnimalHealed {
+                    animal_id: caster.id,
+                    amount,
+                }),
+            ));
+        }
+        "Cluck" => {
+            effects.add(EffectType::DamageBoost, CLUCK_BOOST, caster);
+            for (ally_id, p, _, _, mut ally_ap, mut ally_effects) in animals.iter_mut() {
+                if ally_id.player_id == player_id && position.distance(&p) <= CLUCK_DISTANCE {
+                    let max = state.animals.get(ally_id.id).unwrap().action_points as f32;
+                    ally_ap.amount = (ally_ap.amount + CLUCK_AP).min(max);
+                    ally_effects.add(EffectType::DamageBoost, CLUCK_BOOST, caster);
+                    responses.push((
+                        vec![player_id],
+                        Command::ActionPoints(ActionPointsUpdated {
+                            animal_id: ally_id.id,
+                            action_points: ally_ap.amount,
+                        }),
+                    ));
+                }
+            }
+        }
+        "Flutter" => {
+            effects.add(EffectType::ExtraSteps, FLUTTER_STEPS, caster);
+            effects.add(EffectType::Shield, FLUTTER_SHIELD, caster);
+        }
+        "Sneak" => effects.add(EffectType::Invisible, SNEAK_INVISIBILITY, caster),
+        "Cat's Eye" => {
+            effects.add(EffectType::TrueSight, CATS_EYE_SIGHT, caster);
+            for (_, _, _, _, _, mut ally_effects) in animals
+                .iter_mut()
+                .filter(|(f, ..)| f.player_id == player_id)
+            {
+                ally_effects.add(EffectType::TrueSight, CATS_EYE_SIGHT, caster);
+            }
+        }
+        "Charm" => {
+            for (_, _, _, _, _, mut enemy_effects) in animals
+                .iter_mut()
+                .filter(|(f, ..)| f.player_id != player_id)
+            {
+                enemy_effects.add(EffectType::Pacified, CHARM_PACIFY, caster);
+            }
+        }
+        "Snort" => {
+            for (enemy_id, p, _, _, _, mut enemy_effects) in animals.iter_mut() {
+                if enemy_id.player_id != player_id && p.distance(position) <= SNORT_DISTANCE {
+                    enemy_effects.add(EffectType::Stun, SNORT_STUN, caster);
+                }
+            }
+        }
+        "Cheese lure" => {
+            let target = target.as_ref().unwrap();
+            for (enemy_id, p, _, _, _, mut enemy_effects) in animals.iter_mut() {
+                if enemy_id.player_id != player_id && p.distance(target) <= CHEESE_LURE_RADIUS {
+                    enemy_effects.add(EffectType::Stun, CHEESE_LURE_STUN, caster);
+                }
+            }
+        }
+        "Mud Patch" => {
+            let target = target.as_ref().unwrap();
+            for (enemy_id, p, _, _, _, mut enemy_effects) in animals.iter_mut() {
+                if enemy_id.player_id != player_id
+                    && (p.x - target.x).abs() <= 1
+                    && (p.y - target.y).abs() <= 1
+                {
+                    let mobility = state.animals.get(enemy_id.id).unwrap().mobility;
+                    let slow = (MUD_PATCH_DAMAGE.0, mobility * MUD_PATCH_SLOW_PERCENTS / 100);
+                    enemy_effects.add(EffectType::Mud, MUD_PATCH_DAMAGE, caster);
+                    enemy_effects.add(EffectType::Slow, slow, caster);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    ap.amount -= cost;
+    cooldowns.start(&ability.name, ability.cooldown.unwrap_or(0));
+    for receiver in everyone.iter().rev() {
+        //The opponent must not know where the trap is
+        let visible = *receiver == player_id || ability.name != "It's a trap";
+        responses.insert(
+            0,
+            (
+                vec![*receiver],
+                Command::AbilityUsed(AbilityUsed {
+                    player_id,
+                    animal_id: caster.id,
+                    ability: ability.name.clone(),
+                    position: target
+                        .as_ref()
+                        .filter(|_| visible)
+                        .map(|f| battle::Position { x: f.x, y: f.y }),
+                    cooldown: cooldowns.get(&ability.name),
+                    action_points: if *receiver == player_id {
+                        Some(ap.amount)
+                    } else {
+                        None
+                    },
+                }),
+            ),
+        );
+    }
+    Ok(responses)
+}
+
+fn apply_effects(
+    state: Res<GameState>,
+    mut animals: Query<(
+        &AnimalId,
+        &mut Effects,
+        &mut HitDamage,
+        &mut HitDamageBlock,
+        &mut Mobility,
+    )>,
+) {
+    if state.state != BattleState::GameStage {
+        return;
+    }
+    for (animal_id, mut effects, mut damage, mut block, mut mobility) in animals
+        .iter_mut()
+        .filter(|(_, f, ..)| !f.pending.is_empty())
+    {
+        for mut effect in std::mem::take(&mut effects.pending) {
+            let turns = effect.turns;
+            //Effects applied during the owner's turn should not expire at its end
+            if animal_id.player_id == state.current_turn {
+                effect.turns += 1;
+            }
+            //The same effect is not stacked, it is refreshed
+            if let Some(old) = effects
+                .active
+                .iter_mut()
+                .find(|f| f.effect == effect.effect)
+            {
+                old.turns = old.turns.max(effect.turns);
+                continue;
+            }
+            effect.delta = match effect.effect {
+                EffectType::DamageBoost => damage.amount * effect.value / 100,
+                EffectType::Weakness => damage.amount * effect.value / 100,
+                EffectType::Shield | EffectType::Invulnerable => {
+                    (effect.value as f32).min(100f32 - block.percents) as i32
+                }
+                EffectType::ExtraSteps | EffectType::Slow => effect.value,
+                _ => 0,
+            };
+            match effect.effect {
+                EffectType::DamageBoost => damage.amount += effect.delta,
+                EffectType::Weakness => damage.amount -= effect.delta,
+                EffectType::Shield | EffectType::Invulnerable => {
+                    block.percents += effect.delta as f32
+                }
+                EffectType::ExtraSteps => mobility.squares += effect.delta,
+                EffectType::Slow => mobility.squares = (mobility.squares - effect.delta).max(0),
+                _ => {}
+            }
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::EffectApplied(EffectApplied {
+                    animal_id: animal_id.id,
+                    effect: effect.effect.into(),
+                    turns,
+                    value: effect.value,
+                })),
+            );
+            effects.active.push(effect);
+        }
+    }
+}
+
+//Sends the whole board to the player, who lost the connection or missed some commands
+fn snapshot(
+    mut state: ResMut<GameState>,
+    mut event_reader: EventReader<Event>,
+    animals: Query<(
+        &AnimalId,
+        Option<&Position>,
+        &Health,
+        &Effects,
+        &Mobility,
+        &ActionPoints,
+    )>,
+    eggs: Query<&Position, With<Egg>>,
+    traps: Query<&Trap>,
+) {
+    for my_event in event_reader.iter() {
+        if let BattleMessage::Resume { player_id } | BattleMessage::GetBattleState { player_id } =
+            my_event.message
+        {
+            state.tx.send(
+                &[player_id],
+                Ok(Command::Snapshot(battle_snapshot(
+                    &state,
+                    Some(player_id),
+                    &animals,
+                    &eggs,
+                    &traps,
+                ))),
+            );
+        }
+        if let BattleMessage::Spectate { tx, .. } = &my_event.message {
+            let snapshot = battle_snapshot(&state, None, &animals, &eggs, &traps);
+            if tx
+                .try_send((
+                    Instant::now(),
+                    Ok(BattleCommand {
+                        command: Some(Command::Snapshot(snapshot)),
+                    }),
+                ))
+                .is_ok()
+            {
+                state.tx.add_spectator(tx.clone());
             }
         }
     }
 }
 
+fn battle_snapshot(
+    state: &GameState,
+    //Spectators see only what both players can see
+    viewer: Option<i32>,
+    animals: &Query<(
+        &AnimalId,
+        Option<&Position>,
+        &Health,
+        &Effects,
+        &Mobility,
+        &ActionPoints,
+    )>,
+    eggs: &Query<&Position, With<Egg>>,
+    traps: &Query<&Trap>,
+) -> BattleSnapshot {
+    //Invisible enemies are hidden, unless the player can see them
+    let true_sight = animals.iter().any(|(f, _, _, e, ..)| {
+        Some(f.player_id) == viewer
+            && (e.has(EffectType::TrueSight)
+                || state
+                    .animals
+                    .get(f.id)
+                    .unwrap()
+                    .passive("Night life")
+                    .is_some())
+    });
+    let mut objects: Vec<GameObject> = eggs
+        .iter()
+        .map(|f| GameObject {
+            png_name: Some("Egg".to_string()),
+            x: f.x,
+            y: f.y,
+            object_type: GameObjectType::Solid.into(),
+        })
+        .collect();
+    objects.extend(
+        traps
+            .iter()
+            .filter(|f| Some(f.player_id) == viewer)
+            .map(|f| GameObject {
+                png_name: Some("Trap".to_string()),
+                x: f.x,
+                y: f.y,
+                object_type: GameObjectType::Walkable.into(),
+            }),
+    );
+    BattleSnapshot {
+        state: state.state.into(),
+        map: Some(state.m.map.clone().into()),
+        animals: animals
+            .iter()
+            .map(
+                |(id, position, health, effects, mobility, ap)| AnimalSnapshot {
+                    player_id: id.player_id,
+                    animal_id: id.id,
+                    position: position
+                        .filter(|_| {
+                            Some(id.player_id) == viewer
+                                || true_sight
+                                || !effects.has(EffectType::Invisible)
+                        })
+                        .map(|f| battle::Position { x: f.x, y: f.y }),
+                    health: health.amount,
+                    effects: effects
+                        .active
+                        .iter()
+                        .map(|f| EffectApplied {
+                            animal_id: id.id,
+                            effect: f.effect.into(),
+                            turns: f.turns,
+                            value: f.value,
+                        })
+                        .collect(),
+                    mobility: Some(mobility.squares).filter(|_| Some(id.player_id) == viewer),
+                    action_points: Some(ap.amount).filter(|_| Some(id.player_id) == viewer),
+                },
+            )
+            .collect(),
+        objects,
+        current_turn: Some(state.current_turn),
+        deadline: Some(Timestamp {
+            seconds: state.deadline.timestamp(),
+            nanos: 0,
+        }),
+        opponent_id: viewer.map_or(state.m.player2, |f| state.opponent(f)),
+        invert: Some(state.m.player2) == viewer,
+    }
+}
+
 fn death(
     state: Res<GameState>,
-    animals: Query<(&AnimalId, Entity, &Health)>,
+    mut animals: Query<(&AnimalId, Entity, &mut Health, &mut Cooldowns)>,
     mut commands: Commands,
 ) {
     if state.state != BattleState::GameStage {
         return;
     }
-    for (animal_id, entity, ..) in animals.iter().filter(|(.., health)| health.amount <= 0) {
-        commands.entity(entity).despawn();
-        state
-            .tx
-            .send(BattleMessage::Response {
-                receivers: vec![state.m.player1, state.m.player2],
-                res: Ok(Command::Dead(AnimalDead {
+    for (animal_id, entity, mut health, mut cooldowns) in animals
+        .iter_mut()
+        .filter(|(_, _, health, _)| health.amount <= 0)
+    {
+        let animal = state.animals.get(animal_id.id).unwrap();
+        if let Some(ability) = animal
+            .passive("Nine Lives")
+            .filter(|f| cooldowns.is_ready(&f.name))
+        {
+            health.amount = animal.hp * NINE_LIVES_HP_PERCENTS / 100;
+            cooldowns.start(&ability.name, ability.cooldown.unwrap_or(0));
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::AbilityUsed(AbilityUsed {
+                    player_id: animal_id.player_id,
                     animal_id: animal_id.id,
+                    ability: ability.name.clone(),
+                    position: None,
+                    cooldown: cooldowns.get(&ability.name),
+                    action_points: None,
                 })),
-            })
-            .ok();
+            );
+            state.tx.send(
+                &[state.m.player1, state.m.player2],
+                Ok(Command::Healed(AnimalHealed {
+                    animal_id: animal_id.id,
+                    amount: health.amount,
+                })),
+            );
+            continue;
+        }
+        commands.entity(entity).despawn();
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::Dead(AnimalDead {
+                animal_id: animal_id.id,
+            })),
+        );
+    }
+}
+
+fn connection(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
+    mut state: ResMut<GameState>,
+    mut event_reader: EventReader<Event>,
+) {
+    for my_event in event_reader.iter() {
+        match my_event.message {
+            BattleMessage::Disconnected { player_id } => {
+                let deadline = DateTime::<Utc>::from_utc(
+                    NaiveDateTime::from_timestamp_opt(
+                        clock.now().timestamp() + rules.disconnect_time as i64,
+                        0,
+                    )
+                    .unwrap(),
+                    Utc,
+                );
+                state.disconnected.insert(player_id, deadline);
+            }
+            BattleMessage::Connected { player_id } => {
+                state.disconnected.remove(&player_id);
+            }
+            _ => {}
+        }
     }
 }
+
+fn victory(
+    rules: Res<BattleRules>,
+    clock: Res<BattleClock>,
+    mut state: ResMut<GameState>,
+    animals: Query<&AnimalId>,
+    mut event_reader: EventReader<Event>,
+) {
+    if state.state == BattleState::Finished {
+        return;
+    }
+    let mut result = None;
+    for my_event in event_reader.iter() {
+        if let BattleMessage::Surrender { player_id } = my_event.message {
+            result = Some(MatchResult {
+                winner: Some(state.opponent(player_id)),
+                reason: MatchEndReason::Surrender,
+            });
+            break;
+        }
+    }
+
+    //Players, who did not come back in time, forfeit
+    let now = clock.now();
+    if result.is_none() {
+        let forfeited: Vec<i32> = state
+            .disconnected
+            .iter()
+            .filter(|(_, deadline)| (**deadline - now).num_milliseconds() <= 0)
+            .map(|(&player_id, _)| player_id)
+            .collect();
+        result = match forfeited[..] {
+            [] => None,
+            [player_id] => Some(MatchResult {
+                winner: Some(state.opponent(player_id)),
+                reason: MatchEndReason::Disconnected,
+            }),
+            _ => Some(MatchResult {
+                winner: None,
+                reason: MatchEndReason::Disconnected,
+            }),
+        };
+    }
+
+    if result.is_none() && state.state == BattleState::GameStage {
+        let player1_alive = animals.iter().any(|f| f.player_id == state.m.player1);
+        let player2_alive = animals.iter().any(|f| f.player_id == state.m.player2);
+        result = match (player1_alive, player2_alive) {
+            (true, true) if state.turns >= rules.turn_limit => Some(MatchResult {
+                winner: None,
+                reason: MatchEndReason::TurnLimit,
+            }),
+            (true, true) => None,
+            (true, false) => Some(MatchResult {
+                winner: Some(state.m.player1),
+                reason: MatchEndReason::AllAnimalsDead,
+            }),
+            (false, true) => Some(MatchResult {
+                winner: Some(state.m.player2),
+                reason: MatchEndReason::AllAnimalsDead,
+            }),
+            (false, false) => Some(MatchResult {
+                winner: None,
+                reason: MatchEndReason::AllAnimalsDead,
+            }),
+        };
+    }
+
+    if result.is_some() {
+        state.state = BattleState::Finished;
+        state.result = result;
+        state.tx.send(
+            &[state.m.player1, state.m.player2],
+            Ok(Command::SetState(SetBattleState {
+                state: BattleState::Finished.into(),
+            })),
+        );
+    }
+}
+
 //Events
 
 struct Event {
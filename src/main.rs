@@ -1,19 +1,22 @@
 use std::time::Duration;
 
 use animal_combat_grpc::{
+    content::ContentStore,
     jwt_interceptor, run_battles_loop, run_matchmaking_loop,
     services::{
         auth::{AuthServer, AuthService},
         battle::{BattleServer, BattleService},
         clans::{ClanServer, ClanService},
+        content::{ContentServer, ContentService},
         players::{PlayerServer, PlayerService},
     },
+    ActivePlayers, Outbound,
 };
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
     ConnectOptions,
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc;
 use tonic::{
     transport::{Body, Server},
     Request,
@@ -45,6 +48,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .connect_with(connect_options)
         .await?;
 
+    //Invalid content stops the server before it accepts players
+    let content = ContentStore::load()?;
+    tokio::spawn(content.clone().watch());
+
     let addr = "0.0.0.0:3009".parse().unwrap();
     let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
     health_reporter
@@ -53,20 +60,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Create services
     let auth = AuthService::default();
-    let clans = ClanService::default();
     let players = PlayerService::default();
     let (tx, rx) = mpsc::channel(128);
-    let (tx2, rx2) = broadcast::channel(128);
-    let (battle_tx2, battle_rx2) = broadcast::channel(128);
+    let clans = ClanService::new(tx.clone());
+    let outbound = Outbound::default();
+    let active = ActivePlayers::default();
     let (battle_tx, battle_rx) = mpsc::channel(128);
-    tokio::spawn(run_matchmaking_loop(rx, tx2, battle_tx.clone()));
-    tokio::spawn(run_battles_loop(battle_rx, battle_tx2));
+    tokio::spawn(run_matchmaking_loop(
+        rx,
+        battle_tx.clone(),
+        outbound.clone(),
+        content.clone(),
+        active.clone(),
+    ));
+    tokio::spawn(run_battles_loop(
+        battle_rx,
+        outbound.clone(),
+        active,
+        pool.clone(),
+    ));
     let battle = BattleService {
         sender: tx,
-        receiver: rx2,
-        battle_rx: battle_rx2,
         battle_tx,
+        outbound,
     };
+    let content = ContentService { content };
 
     // Add cors support
     let cors_layer = CorsLayer::new()
@@ -120,6 +138,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .add_service(ClanServer::with_interceptor(clans, jwt_interceptor))
         .add_service(PlayerServer::with_interceptor(players, jwt_interceptor))
         .add_service(BattleServer::with_interceptor(battle, jwt_interceptor))
+        .add_service(ContentServer::with_interceptor(content, jwt_interceptor))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::{seq::SliceRandom, RngCore};
+use skillratings::sticko::{expected_score, StickoConfig, StickoRating};
+
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+//Player waiting in the queue, as seen by the strategy
+pub struct Candidate {
+    pub id: i32,
+    pub rating: StickoRating,
+    pub waited: Duration,
+    //The opponent of the last match, and how long ago it was
+    pub last_opponent: Option<(i32, Duration)>,
+}
+
+pub trait MatchmakingStrategy: Send {
+    //Picks the opponent for the player, None keeps the player waiting
+    fn find_opponent(
+        &self,
+        player: &Candidate,
+        candidates: &[Candidate],
+        rng: &mut dyn RngCore,
+    ) -> Option<i32>;
+
+    //Maximum glory difference the player can be matched with now, shown in the queue status
+    fn search_range(&self, player: &Candidate) -> i32;
+}
+
+//Matches the players whose chances to win are closest to equal.
+//Expected score takes deviations into account, so unsure ratings are matched more freely
+pub struct StickoStrategy {
+    //Minimal expected score of the weaker player, when they just joined
+    pub min_expected_score: f64,
+    //How much the minimal expected score drops every second of waiting
+    pub relax_per_second: f64,
+    //The minimal expected score never drops below this
+    pub lowest_expected_score: f64,
+    //Players are not matched again with their last opponent for this time
+    pub rematch_cooldown: Duration,
+    pub config: StickoConfig,
+}
+
+impl Default for StickoStrategy {
+    fn default() -> Self {
+        Self {
+            min_expected_score: 0.4,
+            relax_per_second: 0.005,
+            lowest_expected_score: 0.2,
+            rematch_cooldown: Duration::minutes(5),
+            config: StickoConfig::new(),
+        }
+    }
+}
+
+impl StickoStrategy {
+    fn min_score(&self, player: &Candidate) -> f64 {
+        let relaxed = self.min_expected_score
+            - self.relax_per_second * player.waited.num_milliseconds() as f64 / 1000f64;
+        relaxed.max(self.lowest_expected_score)
+    }
+
+    fn is_rematch(&self, player: &Candidate, other: &Candidate) -> bool {
+        let recent = |candidate: &Candidate, opponent: i32| {
+            candidate
+                .last_opponent
+                .is_some_and(|(id, since)| id == opponent && since < self.rematch_cooldown)
+        };
+        recent(player, other.id) || recent(other, player.id)
+    }
+}
+
+impl MatchmakingStrategy for StickoStrategy {
+    fn find_opponent(
+        &self,
+        player: &Candidate,
+        candidates: &[Candidate],
+        rng: &mut dyn RngCore,
+    ) -> Option<i32> {
+        let mut best: Vec<i32> = Vec::new();
+        let mut best_score = 0f64;
+        for other in candidates {
+            if other.id == player.id || self.is_rematch(player, other) {
+                continue;
+            }
+            let (score, other_score) = expected_score(&player.rating, &other.rating, &self.config);
+            let weaker_score = score.min(other_score);
+            //The one who waits longer decides how unfair the match can be
+            if weaker_score < self.min_score(player).min(self.min_score(other)) {
+                continue;
+            }
+            if weaker_score > best_score + f64::EPSILON {
+                best_score = weaker_score;
+                best.clear();
+            }
+            if (weaker_score - best_score).abs() <= f64::EPSILON {
+                best.push(other.id);
+            }
+        }
+        best.choose(rng).copied()
+    }
+
+    //Rating difference with the minimal expected score, if both deviations were zero
+    fn search_range(&self, player: &Candidate) -> i32 {
+        let score = self.min_score(player);
+        (400f64 * (1f64 / score - 1f64).log10()) as i32
+    }
+}
@@ -0,0 +1,55 @@
+use bevy_ecs::prelude::Resource;
+use serde::{Deserialize, Serialize};
+
+//Everything a game mode can change, timers are in seconds
+#[derive(Resource, Serialize, Deserialize, Clone, Debug)]
+pub struct BattleRules {
+    //Animals of every player
+    pub team_size: usize,
+    pub pick_time: u64,
+    pub place_time: u64,
+    pub turn_time: u64,
+    //The battle is a draw after this many turns
+    pub turn_limit: i32,
+    //Players are matched with a bot after waiting this long in the queue
+    #[serde(default = "default_bot_wait_time")]
+    pub bot_wait_time: u64,
+    //A disconnected player loses, if they do not come back in this time
+    #[serde(default = "default_disconnect_time")]
+    pub disconnect_time: u64,
+    //A player loses, if they do not open their battle stream in this time after the battle is created
+    #[serde(default = "default_connect_time")]
+    pub connect_time: u64,
+}
+
+fn default_bot_wait_time() -> u64 {
+    60
+}
+
+fn default_disconnect_time() -> u64 {
+    60
+}
+
+fn default_connect_time() -> u64 {
+    60
+}
+
+impl BattleRules {
+    //Rules from the file in BATTLE_RULES, or the default ones
+    pub fn load() -> Self {
+        match std::env::var("BATTLE_RULES") {
+            Ok(path) => Self::from_file(&path)
+                .unwrap_or_else(|e| panic!("Failed to load battle rules from {path}: {e}")),
+            Err(_) => serde_json::from_str(include_str!("../data/rules.json")).unwrap(),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    //Animals of both players
+    pub fn pick_count(&self) -> usize {
+        self.team_size * 2
+    }
+}
@@ -1,29 +1,37 @@
 use std::pin::Pin;
+use std::time::Duration;
 
 use futures::Stream;
+use prost::Message;
+use prost_types::Timestamp;
 use skillratings::sticko::StickoRating;
 use sqlx::{Pool, Postgres};
 use tokio::sync::{
-    broadcast::Receiver,
     mpsc::{self, Sender},
+    oneshot,
 };
+use tokio::time;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 use tracing::warn;
 
-use crate::{BattleMessage, MatchmakerMessage};
+use crate::bot::is_bot;
+use crate::{BattleMessage, MatchmakerMessage, MatchmakerUpdate, Outbound};
 
 use super::auth::Claims;
 
 pub type BattleServer<T> = battle_server::BattleServer<T>;
 
+const MAX_SPECTATE_DELAY: u32 = 300;
+//Commands wait here until the delay passes
+const SPECTATOR_BUFFER_SIZE: usize = 1024;
+
 tonic::include_proto!("battle");
 
 pub struct BattleService {
     pub sender: Sender<MatchmakerMessage>,
-    pub receiver: Receiver<MatchmakerMessage>,
     pub battle_tx: Sender<BattleMessage>,
-    pub battle_rx: Receiver<BattleMessage>,
+    pub outbound: Outbound,
 }
 
 #[tonic::async_trait]
@@ -62,7 +70,99 @@ impl battle_server::Battle for BattleService {
         Ok(Response::new(()))
     }
 
-    type FindMatchStream = Pin<Box<dyn Stream<Item = Result<MatchFound, Status>> + Send>>;
+    async fn accept_match(&self, request: Request<()>) -> Result<Response<()>, Status> {
+        let (_, extensions, _) = request.into_parts();
+        let credetials = extensions.get::<Claims>().unwrap();
+        self.sender
+            .send(MatchmakerMessage::AcceptMatch { id: credetials.id })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        Ok(Response::new(()))
+    }
+
+    async fn decline_match(&self, request: Request<()>) -> Result<Response<()>, Status> {
+        let (_, extensions, _) = request.into_parts();
+        let credetials = extensions.get::<Claims>().unwrap();
+        self.sender
+            .send(MatchmakerMessage::DeclineMatch { id: credetials.id })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        Ok(Response::new(()))
+    }
+
+    async fn challenge_player(
+        &self,
+        request: Request<ChallengeRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap();
+        let credetials = extensions.get::<Claims>().unwrap();
+        if request.player_id == credetials.id {
+            return Err(Status::invalid_argument("You cannot challenge yourself"));
+        }
+        sqlx::query("SELECT id FROM players WHERE id = $1")
+            .bind(request.player_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| Status::data_loss(format!("Database error: {e}")))?
+            .ok_or(Status::not_found("Player not found"))?;
+
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(MatchmakerMessage::ChallengePlayer {
+                id: credetials.id,
+                player_id: request.player_id,
+                ranked: !request.unranked,
+                tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        rx.await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))??;
+        Ok(Response::new(()))
+    }
+
+    async fn accept_challenge(
+        &self,
+        request: Request<ChallengeAnswer>,
+    ) -> Result<Response<()>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let credetials = extensions.get::<Claims>().unwrap();
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(MatchmakerMessage::AcceptChallenge {
+                id: credetials.id,
+                challenger_id: request.challenger_id,
+                tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        rx.await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))??;
+        Ok(Response::new(()))
+    }
+
+    async fn decline_challenge(
+        &self,
+        request: Request<ChallengeAnswer>,
+    ) -> Result<Response<()>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let credetials = extensions.get::<Claims>().unwrap();
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(MatchmakerMessage::DeclineChallenge {
+                id: credetials.id,
+                challenger_id: request.challenger_id,
+                tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        rx.await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))??;
+        Ok(Response::new(()))
+    }
+
+    type FindMatchStream = Pin<Box<dyn Stream<Item = Result<MatchmakingUpdate, Status>> + Send>>;
 
     async fn find_match(
         &self,
@@ -74,58 +174,96 @@ impl battle_server::Battle for BattleService {
 
         let (tx, rx) = mpsc::channel(128);
 
-        let mut rcv = self.receiver.resubscribe();
         let player_id = credetials.id;
+        let (updates_tx, mut updates) = mpsc::channel(16);
+        self.sender
+            .send(MatchmakerMessage::Subscribe {
+                id: player_id,
+                tx: updates_tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
         tokio::spawn(async move {
-            while let Ok(msg) = rcv.recv().await {
-                match msg {
-                    MatchmakerMessage::MatchFound(m)
-                        if m.player1 == player_id || m.player2 == player_id =>
-                    {
-                        let res = sqlx::query_as(
-                            "SELECT glory,
-                                    nickname,
-                                    clan_name
-                            FROM players
-                            LEFT JOIN clans ON clans.id = players.clan_id
-                            WHERE players.id = $1",
-                        )
-                        .bind(if m.player1 == player_id {
+            while let Some(update) = updates.recv().await {
+                let update = match update {
+                    MatchmakerUpdate::Status(status) => matchmaking_update::Update::Status(status),
+                    MatchmakerUpdate::MatchStarted => {
+                        matchmaking_update::Update::Started(MatchStarted {})
+                    }
+                    MatchmakerUpdate::MatchCancelled { requeued } => {
+                        matchmaking_update::Update::Cancelled(MatchCancelled { requeued })
+                    }
+                    MatchmakerUpdate::ChallengeReceived {
+                        challenger_id,
+                        ranked,
+                        deadline,
+                    } => matchmaking_update::Update::Challenge(ChallengeReceived {
+                        challenger_id,
+                        unranked: !ranked,
+                        deadline: Some(Timestamp {
+                            seconds: deadline.timestamp(),
+                            nanos: 0,
+                        }),
+                    }),
+                    MatchmakerUpdate::ChallengeDeclined { player_id } => {
+                        matchmaking_update::Update::ChallengeDeclined(ChallengeDeclined {
+                            player_id,
+                        })
+                    }
+                    MatchmakerUpdate::MatchFound { m, accept_deadline } => {
+                        let opponent_id = if m.player1 == player_id {
                             m.player2
                         } else {
                             m.player1
-                        })
-                        .fetch_one(&pool)
-                        .await;
-                        if let Ok((glory, nickname, clan_name)) = res {
-                            if tx
-                                .send(Result::<_, Status>::Ok(MatchFound {
-                                    opponent_id: if m.player1 == player_id {
-                                        m.player2
-                                    } else {
-                                        m.player1
-                                    },
+                        };
+                        let res = if is_bot(opponent_id) {
+                            Ok((0, Some("Bot".to_string()), None))
+                        } else {
+                            sqlx::query_as(
+                                "SELECT glory,
+                                        nickname,
+                                        clan_name
+                                FROM players
+                                LEFT JOIN clans ON clans.id = players.clan_id
+                                WHERE players.id = $1",
+                            )
+                            .bind(opponent_id)
+                            .fetch_one(&pool)
+                            .await
+                        };
+                        match res {
+                            Ok((glory, nickname, clan_name)) => {
+                                matchmaking_update::Update::MatchFound(MatchFound {
+                                    opponent_id,
                                     nickname,
                                     clan_name,
                                     glory,
                                     map: Some(m.map.into()),
                                     invert: m.player2 == player_id,
-                                }))
-                                .await
-                                .is_err()
-                            {
-                                break;
+                                    accept_deadline: accept_deadline.map(|f| Timestamp {
+                                        seconds: f.timestamp(),
+                                        nanos: 0,
+                                    }),
+                                    bot: is_bot(opponent_id),
+                                })
+                            }
+                            Err(e) => {
+                                tx.send(Err(Status::data_loss(format!("Database error: {e}"))))
+                                    .await
+                                    .ok();
+                                continue;
                             }
-                        } else {
-                            tx.send(Result::<_, Status>::Err(Status::data_loss(format!(
-                                "Database error: {}",
-                                res.err().unwrap()
-                            ))))
-                            .await
-                            .ok();
                         }
                     }
-                    _ => continue,
+                };
+                if tx
+                    .send(Ok(MatchmakingUpdate {
+                        update: Some(update),
+                    }))
+                    .await
+                    .is_err()
+                {
+                    break;
                 }
             }
         });
@@ -136,6 +274,92 @@ impl battle_server::Battle for BattleService {
         ))
     }
 
+    async fn get_replay(
+        &self,
+        request: Request<ReplayRequest>,
+    ) -> Result<Response<Replay>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap();
+        let credetials = extensions.get::<Claims>().unwrap();
+
+        //Players can watch only their own battles
+        let (replay,): (Vec<u8>,) = sqlx::query_as(
+            "SELECT replay
+            FROM replays
+            JOIN matches ON matches.id = replays.match_id
+            WHERE matches.id = $1
+              AND (player1_id = $2 OR player2_id = $2)",
+        )
+        .bind(request.match_id)
+        .bind(credetials.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| Status::data_loss(format!("Database error: {e}")))?
+        .ok_or_else(|| Status::not_found("Replay not found"))?;
+        let replay = Replay::decode(replay.as_slice())
+            .map_err(|e| Status::data_loss(format!("Replay is corrupted: {e}")))?;
+        Ok(Response::new(Replay {
+            match_id: request.match_id,
+            ..replay
+        }))
+    }
+
+    type SpectateBattleStream = Pin<Box<dyn Stream<Item = Result<BattleCommand, Status>> + Send>>;
+
+    async fn spectate_battle(
+        &self,
+        request: Request<SpectateRequest>,
+    ) -> Result<Response<Self::SpectateBattleStream>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap();
+        let credetials = extensions.get::<Claims>().unwrap();
+        if request.delay_seconds > MAX_SPECTATE_DELAY {
+            return Err(Status::invalid_argument("Delay is too long"));
+        }
+        if sqlx::query(
+            "SELECT NULL
+            FROM players
+            JOIN players AS spectators ON spectators.clan_id = players.clan_id
+            WHERE players.id = $1
+              AND spectators.id = $2
+              AND players.id != spectators.id",
+        )
+        .bind(request.player_id)
+        .bind(credetials.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| Status::data_loss(format!("Database error: {e}")))?
+        .is_none()
+        {
+            return Err(Status::permission_denied(
+                "Only clanmates can spectate the battle",
+            ));
+        }
+
+        let (tx, rx) = mpsc::channel(128);
+        let (spectator_tx, mut spectator_rx) = mpsc::channel(SPECTATOR_BUFFER_SIZE);
+        self.battle_tx
+            .send(BattleMessage::Spectate {
+                player_id: request.player_id,
+                tx: spectator_tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Battles are closed"))?;
+        let delay = Duration::from_secs(request.delay_seconds as u64);
+        tokio::spawn(async move {
+            while let Some((time, res)) = spectator_rx.recv().await {
+                time::sleep_until(time + delay).await;
+                if tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let out_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(out_stream) as Self::SpectateBattleStream
+        ))
+    }
+
     type BattleMessagesStream = Pin<Box<dyn Stream<Item = Result<BattleCommand, Status>> + Send>>;
 
     async fn battle_messages(
@@ -145,109 +369,48 @@ impl battle_server::Battle for BattleService {
         let (_, extensions, mut in_stream) = request.into_parts();
         let credetials = extensions.get::<Claims>().unwrap();
 
-        let (tx, rx) = mpsc::channel(128);
-
-        let mut rcv = self.battle_rx.resubscribe();
-        let sender = self.battle_tx.clone();
         let player_id = credetials.id;
+        let rx = self.outbound.register(player_id);
+        let outbound = self.outbound.clone();
+        let sender = self.battle_tx.clone();
+        //Resolves, when the stream of commands is dropped
+        let (closed_tx, closed_rx) = oneshot::channel::<()>();
 
         tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    Some(result) = in_stream.next() => {
-                        match result {
-                            Ok(v) => {
-                                if let Some(msg) = v.message {
-                                    match msg {
-                                        client_battle_message::Message::Pick(v) => {
-                                            sender
-                                                .send(BattleMessage::Pick {
-                                                    player_id,
-                                                    cmd: v,
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::Ready(_) => {
-                                            sender
-                                                .send(BattleMessage::Ready {
-                                                    player_id
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::Place(animals) => {
-                                            sender
-                                                .send(BattleMessage::PlacePlayerAnimals {
-                                                    player_id,
-                                                    animals
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::Use(v) => {
-                                            sender
-                                                .send(BattleMessage::UsePlayerAnimal {
-                                                    player_id,
-                                                    animal: v,
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::Move(v) => {
-                                            sender
-                                                .send(BattleMessage::MovePlayerAnimal {
-                                                    player_id,
-                                                    animal: v,
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::End(_) => {
-                                            sender
-                                                .send(BattleMessage::EndTurn {
-                                                    player_id
-                                                })
-                                                .await
-                                                .ok();
-                                        },
-                                        client_battle_message::Message::Damage(v) => {
-                                            sender
-                                                .send(BattleMessage::DamagePlayerAnimal {
-                                                    player_id,
-                                                    animal: v
-                                                })
-                                                .await
-                                                .ok();
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                warn!("Client error in streaming {}", err);
-                                break;
-                            }
-                        }
-                    },
-                    Ok(value) = rcv.recv() => {
-                        match value {
-                            BattleMessage::Response{ receivers, res } => {
-                                if res.is_err() {
-                                    println!("{:?}", res.as_ref().err());
-                                }
-                                if receivers.contains(&player_id) {
-                                    tx.send(res.map(|cmd| BattleCommand {
-                                        command: Some(cmd)
-                                    })).await.ok();
-                                }
-                            },
-                            _ => continue
+            sender
+                .send(BattleMessage::Connected { player_id })
+                .await
+                .ok();
+            while let Some(result) = in_stream.next().await {
+                match result {
+                    Ok(v) => {
+                        if let Some(msg) = v.message {
+                            sender
+                                .send(BattleMessage::from_client(player_id, msg))
+                                .await
+                                .ok();
                         }
                     }
+                    Err(err) => {
+                        warn!("Client error in streaming {}", err);
+                        break;
+                    }
                 }
             }
+            //A client, who stopped sending, still watches the battle.
+            //They are disconnected, when they stop receiving too
+            closed_rx.await.ok();
+            outbound.unregister(player_id);
+            //The player forfeits, if they do not come back in time
+            sender
+                .send(BattleMessage::Disconnected { player_id })
+                .await
+                .ok();
+        });
+        let out_stream = ReceiverStream::new(rx).map(move |f| {
+            let _closed = &closed_tx;
+            f
         });
-        let out_stream = ReceiverStream::new(rx);
         Ok(Response::new(
             Box::pin(out_stream) as Self::BattleMessagesStream
         ))
@@ -2,39 +2,98 @@ use chrono::{DateTime, Utc};
 use futures::Stream;
 use prost_types::Timestamp;
 use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::{
     broadcast::{self, Receiver, Sender},
-    mpsc,
+    mpsc, oneshot,
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 use super::auth::Claims;
+use crate::MatchmakerMessage;
 
 pub type ClanServer<T> = clan_server::ClanServer<T>;
 
 const CLAN_CREATION_PRICE: i32 = 1000;
 const MAX_MEMBERS: i32 = 50;
+const FRIENDLY_BATTLE_TIME: u64 = 300;
 
 tonic::include_proto!("clans");
 
+//Friendly battle offered in the clan chat, until someone takes it
+struct FriendlyBattle {
+    clan_id: i32,
+    player_id: i32,
+    nickname: String,
+}
+
 pub struct ClanService {
     sender: Sender<(i32, ClanMesage)>,
     receiver: Receiver<(i32, ClanMesage)>,
+    matchmaker: mpsc::Sender<MatchmakerMessage>,
+    //Open offers by the id of their chat message
+    friendly_battles: Arc<Mutex<HashMap<i32, FriendlyBattle>>>,
 }
 
 impl ClanService {
-    pub fn new() -> Self {
+    pub fn new(matchmaker: mpsc::Sender<MatchmakerMessage>) -> Self {
         let (sender, receiver) = broadcast::channel(16);
-        Self { sender, receiver }
+        Self {
+            sender,
+            receiver,
+            matchmaker,
+            friendly_battles: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 }
 
-impl Default for ClanService {
-    fn default() -> Self {
-        Self::new()
-    }
+//Stores the system message about a friendly battle and streams it to the clan.
+//The id of the offer message becomes the id of the friendly battle
+async fn send_friendly_battle_message(
+    pool: &Pool<Postgres>,
+    sender: &Sender<(i32, ClanMesage)>,
+    clan_id: i32,
+    player_id: i32,
+    text: String,
+    message_type: SqlMessageType,
+    friendly_battle_id: Option<i32>,
+) -> Result<i32, Status> {
+    let time = Utc::now();
+    let (id,): (i32,) = sqlx::query_as(
+        "INSERT INTO messages (player_id, created_at, content, msg_type, chat_room_id)
+        SELECT $1, $2, $3, $4, chat_room_id
+        FROM clans
+        WHERE id = $5
+        RETURNING id",
+    )
+    .bind(player_id)
+    .bind(time)
+    .bind(&text)
+    .bind(message_type)
+    .bind(clan_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| Status::data_loss(format!("Database error: {e}")))?;
+    sender
+        .send((
+            clan_id,
+            ClanMesage {
+                time: Some(Timestamp {
+                    seconds: time.timestamp(),
+                    nanos: 0,
+                }),
+                message: Some(TextMessage { text }),
+                message_type: Into::<MessageType>::into(message_type).into(),
+                sender: None,
+                friendly_battle_id: friendly_battle_id.or(Some(id)),
+            },
+        ))
+        .ok();
+    Ok(id)
 }
 
 #[derive(sqlx::Type, PartialEq)]
@@ -45,7 +104,7 @@ enum SqlClanType {
     InviteOnly,
 }
 
-#[derive(sqlx::Type, PartialEq)]
+#[derive(sqlx::Type, PartialEq, Clone, Copy)]
 #[sqlx(type_name = "message_type")]
 enum SqlMessageType {
     SystemPositive,
@@ -83,6 +142,21 @@ impl From<SqlMessageType> for MessageType {
     }
 }
 
+//Clan of the player and their name in the chat
+async fn clan_member(pool: &Pool<Postgres>, player_id: i32) -> Result<(i32, String), Status> {
+    let (clan_id, nickname): (Option<i32>, Option<String>) = sqlx::query_as(
+        "SELECT clan_id, nickname
+        FROM players
+        WHERE id = $1",
+    )
+    .bind(player_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| Status::data_loss(format!("Database error: {e}")))?;
+    let clan_id = clan_id.ok_or(Status::permission_denied("Player is not in clan"))?;
+    Ok((clan_id, nickname.unwrap_or("Anonymous".to_string())))
+}
+
 #[tonic::async_trait]
 impl clan_server::Clan for ClanService {
     async fn create_clan(&self, request: Request<ClanInfo>) -> Result<Response<()>, Status> {
@@ -450,6 +524,7 @@ impl clan_server::Clan for ClanService {
                         message: Some(TextMessage { text: message }),
                         message_type: MessageType::SystemPositive as i32,
                         sender: None,
+                        friendly_battle_id: None,
                     },
                 ))
                 .unwrap();
@@ -522,6 +597,7 @@ impl clan_server::Clan for ClanService {
                     message: Some(TextMessage { text: message }),
                     message_type: MessageType::SystemNegative as i32,
                     sender: None,
+                    friendly_battle_id: None,
                 },
             ))
             .unwrap();
@@ -599,6 +675,7 @@ impl clan_server::Clan for ClanService {
                             nickname,
                             player_id: credetials.id,
                         }),
+                        friendly_battle_id: None,
                     },
                 ))
                 .unwrap();
@@ -697,6 +774,7 @@ impl clan_server::Clan for ClanService {
                                     nickname,
                                     player_id,
                                 }),
+                                friendly_battle_id: None,
                             },
                             row_num,
                         )
@@ -779,6 +857,7 @@ impl clan_server::Clan for ClanService {
                             nickname,
                             player_id,
                         }),
+                        friendly_battle_id: None,
                     }
                 },
             )
@@ -830,4 +909,164 @@ impl clan_server::Clan for ClanService {
             Box::pin(output_stream) as Self::ReceiveMessageStream
         ))
     }
+
+    async fn offer_friendly_battle(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<FriendlyBattleId>, Status> {
+        let (_, extensions, _) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap().clone();
+        let credetials = extensions.get::<Claims>().unwrap();
+
+        let (clan_id, nickname) = clan_member(&pool, credetials.id).await?;
+        if self
+            .friendly_battles
+            .lock()
+            .unwrap()
+            .values()
+            .any(|f| f.player_id == credetials.id)
+        {
+            return Err(Status::already_exists("Friendly battle is already offered"));
+        }
+
+        let id = send_friendly_battle_message(
+            &pool,
+            &self.sender,
+            clan_id,
+            credetials.id,
+            format!("{nickname} is looking for a friendly battle"),
+            SqlMessageType::SystemPositive,
+            None,
+        )
+        .await?;
+        self.friendly_battles.lock().unwrap().insert(
+            id,
+            FriendlyBattle {
+                clan_id,
+                player_id: credetials.id,
+                nickname: nickname.clone(),
+            },
+        );
+
+        //Nobody took the offer in time
+        let (friendly_battles, sender) = (self.friendly_battles.clone(), self.sender.clone());
+        let player_id = credetials.id;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(FRIENDLY_BATTLE_TIME)).await;
+            if friendly_battles.lock().unwrap().remove(&id).is_none() {
+                return;
+            }
+            send_friendly_battle_message(
+                &pool,
+                &sender,
+                clan_id,
+                player_id,
+                format!("Friendly battle of {nickname} has expired"),
+                SqlMessageType::SystemNegative,
+                Some(id),
+            )
+            .await
+            .ok();
+        });
+        Ok(Response::new(FriendlyBattleId { id }))
+    }
+
+    async fn accept_friendly_battle(
+        &self,
+        request: Request<FriendlyBattleId>,
+    ) -> Result<Response<()>, Status> {
+        let (_, extensions, request) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap().clone();
+        let credetials = extensions.get::<Claims>().unwrap();
+
+        let (clan_id, nickname) = clan_member(&pool, credetials.id).await?;
+        let offer = {
+            let mut friendly_battles = self.friendly_battles.lock().unwrap();
+            match friendly_battles.get(&request.id) {
+                Some(offer) if offer.clan_id != clan_id => {
+                    return Err(Status::not_found("Friendly battle not found"))
+                }
+                Some(offer) if offer.player_id == credetials.id => {
+                    return Err(Status::permission_denied(
+                        "You cannot accept your own friendly battle",
+                    ))
+                }
+                Some(_) => friendly_battles.remove(&request.id).unwrap(),
+                None => return Err(Status::not_found("Friendly battle not found")),
+            }
+        };
+
+        let (result_tx, mut result_rx) = mpsc::channel(1);
+        let (tx, rx) = oneshot::channel();
+        self.matchmaker
+            .send(MatchmakerMessage::FriendlyBattle {
+                player1: offer.player_id,
+                player2: credetials.id,
+                result_tx,
+                tx,
+            })
+            .await
+            .map_err(|_| Status::aborted("Matchmaking is closed"))?;
+        let res = rx
+            .await
+            .unwrap_or(Err(Status::aborted("Matchmaking is closed")));
+        if let Err(status) = res {
+            //Someone else can take it
+            self.friendly_battles
+                .lock()
+                .unwrap()
+                .insert(request.id, offer);
+            return Err(status);
+        }
+
+        send_friendly_battle_message(
+            &pool,
+            &self.sender,
+            clan_id,
+            credetials.id,
+            format!(
+                "{nickname} accepted the friendly battle of {}",
+                offer.nickname
+            ),
+            SqlMessageType::SystemPositive,
+            Some(request.id),
+        )
+        .await?;
+
+        let sender = self.sender.clone();
+        let player_id = credetials.id;
+        tokio::spawn(async move {
+            let Some(result) = result_rx.recv().await else {
+                return;
+            };
+            let text = match result.winner_id {
+                Some(winner_id) if winner_id == player_id => {
+                    format!(
+                        "{nickname} won the friendly battle against {}",
+                        offer.nickname
+                    )
+                }
+                Some(_) => format!(
+                    "{} won the friendly battle against {nickname}",
+                    offer.nickname
+                ),
+                None => format!(
+                    "Friendly battle of {} and {nickname} ended in a draw",
+                    offer.nickname
+                ),
+            };
+            send_friendly_battle_message(
+                &pool,
+                &sender,
+                clan_id,
+                player_id,
+                text,
+                SqlMessageType::SystemPositive,
+                Some(request.id),
+            )
+            .await
+            .ok();
+        });
+        Ok(Response::new(()))
+    }
 }
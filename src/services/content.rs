@@ -0,0 +1,39 @@
+use tonic::{Request, Response, Status};
+
+use crate::content::ContentStore;
+
+pub type ContentServer<T> = content_server::ContentServer<T>;
+
+tonic::include_proto!("content");
+
+pub struct ContentService {
+    pub content: ContentStore,
+}
+
+#[tonic::async_trait]
+impl content_server::Content for ContentService {
+    async fn list_animals(&self, _: Request<()>) -> Result<Response<AnimalList>, Status> {
+        let content = self.content.current();
+        Ok(Response::new(AnimalList {
+            version: content.version,
+            animals: content.animals.animals.iter().map(|f| f.into()).collect(),
+        }))
+    }
+
+    async fn list_maps(&self, _: Request<()>) -> Result<Response<MapList>, Status> {
+        let content = self.content.current();
+        Ok(Response::new(MapList {
+            version: content.version,
+            maps: content.maps.maps.iter().map(|f| f.clone().into()).collect(),
+        }))
+    }
+
+    async fn get_content_version(
+        &self,
+        _: Request<()>,
+    ) -> Result<Response<ContentVersion>, Status> {
+        Ok(Response::new(ContentVersion {
+            version: self.content.current().version,
+        }))
+    }
+}
@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod battle;
 pub mod clans;
+pub mod content;
 pub mod players;
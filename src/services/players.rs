@@ -1,3 +1,5 @@
+use chrono::{DateTime, Utc};
+use prost_types::Timestamp;
 use sqlx::{Pool, Postgres};
 use tonic::{Request, Response, Status};
 
@@ -98,4 +100,103 @@ impl player_server::Player for PlayerService {
             }),
         }))
     }
+
+    async fn get_match_history(
+        &self,
+        request: Request<Pagination>,
+    ) -> Result<Response<MatchHistory>, Status> {
+        let (_, extensions, pagination) = request.into_parts();
+        let pool = extensions.get::<Pool<Postgres>>().unwrap();
+        let credetials = extensions.get::<Claims>().unwrap();
+
+        let matches: Vec<MatchRecord> = sqlx::query_as(
+            "WITH m AS
+            (SELECT id,
+                    (CASE WHEN player1_id = $1 THEN player2_id ELSE player1_id END) AS opponent_id,
+                    map_name,
+                    (CASE WHEN player1_id = $1 THEN player1_animals ELSE player2_animals END) AS animals,
+                    (CASE WHEN player1_id = $1 THEN player2_animals ELSE player1_animals END) AS opponent_animals,
+                    winner_id,
+                    (CASE WHEN player1_id = $1 THEN player1_glory_delta ELSE player2_glory_delta END) AS glory_delta,
+                    started_at,
+                    finished_at
+             FROM matches
+             WHERE player1_id = $1
+               OR player2_id = $1)
+          SELECT m.id,
+                 opponent_id,
+                 nickname,
+                 map_name,
+                 animals,
+                 opponent_animals,
+                 winner_id,
+                 glory_delta,
+                 started_at,
+                 finished_at
+          FROM m
+          JOIN players ON players.id = m.opponent_id
+          ORDER BY finished_at DESC
+          OFFSET $2
+          LIMIT $3",
+        )
+        .bind(credetials.id)
+        .bind(pagination.offset.unwrap_or(0))
+        .bind(pagination.limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| Status::data_loss(format!("Database error: {e}")))?
+        .into_iter()
+        .map(
+            |(
+                id,
+                opponent_id,
+                opponent_nickname,
+                map_name,
+                animals,
+                opponent_animals,
+                winner_id,
+                glory_delta,
+                started_at,
+                finished_at,
+            ): (
+                i32,
+                i32,
+                Option<String>,
+                String,
+                Vec<i32>,
+                Vec<i32>,
+                Option<i32>,
+                i32,
+                DateTime<Utc>,
+                DateTime<Utc>,
+            )| {
+                MatchRecord {
+                    id,
+                    opponent_id,
+                    opponent_nickname,
+                    map_name,
+                    animals,
+                    opponent_animals,
+                    outcome: match winner_id {
+                        Some(winner_id) if winner_id == credetials.id => MatchOutcome::Victory,
+                        Some(_) => MatchOutcome::Defeat,
+                        None => MatchOutcome::Draw,
+                    }
+                    .into(),
+                    glory_delta,
+                    duration: (finished_at - started_at).num_seconds() as i32,
+                    finished_at: Some(Timestamp {
+                        seconds: finished_at.timestamp(),
+                        nanos: 0,
+                    }),
+                }
+            },
+        )
+        .collect();
+
+        Ok(Response::new(MatchHistory {
+            offset: pagination.offset.unwrap_or(0),
+            matches,
+        }))
+    }
 }
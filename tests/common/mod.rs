@@ -1,14 +1,17 @@
 use animal_combat_grpc::{
+    content::ContentStore,
     jwt_interceptor, run_battles_loop, run_matchmaking_loop,
     services::{
         auth::{AuthServer, AuthService},
         battle::{BattleServer, BattleService},
         clans::{ClanServer, ClanService},
+        content::{ContentServer, ContentService},
         players::{PlayerServer, PlayerService},
     },
+    ActivePlayers, Outbound,
 };
 use sqlx::PgPool;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc;
 
 use std::time::Duration;
 use tonic::{
@@ -23,19 +26,32 @@ pub async fn get_test_channel(pool: PgPool) -> Result<Channel, Box<dyn std::erro
 
     //Create services
     let auth = AuthService::default();
-    let clans = ClanService::default();
     let players = PlayerService::default();
     let (tx, rx) = mpsc::channel(128);
-    let (tx2, rx2) = broadcast::channel(128);
-    let (battle_tx2, battle_rx2) = broadcast::channel(128);
+    let clans = ClanService::new(tx.clone());
+    let outbound = Outbound::default();
+    let active = ActivePlayers::default();
     let (battle_tx, battle_rx) = mpsc::channel(128);
-    tokio::spawn(run_matchmaking_loop(rx, tx2, battle_tx.clone()));
-    tokio::spawn(run_battles_loop(battle_rx, battle_tx2));
+    tokio::spawn(run_matchmaking_loop(
+        rx,
+        battle_tx.clone(),
+        outbound.clone(),
+        ContentStore::default(),
+        active.clone(),
+    ));
+    tokio::spawn(run_battles_loop(
+        battle_rx,
+        outbound.clone(),
+        active,
+        pool.clone(),
+    ));
     let battle = BattleService {
         sender: tx,
-        receiver: rx2,
-        battle_rx: battle_rx2,
         battle_tx,
+        outbound,
+    };
+    let content = ContentService {
+        content: ContentStore::default(),
     };
 
     let layer = tower::ServiceBuilder::new()
@@ -53,6 +69,7 @@ pub async fn get_test_channel(pool: PgPool) -> Result<Channel, Box<dyn std::erro
             .add_service(ClanServer::with_interceptor(clans, jwt_interceptor))
             .add_service(PlayerServer::with_interceptor(players, jwt_interceptor))
             .add_service(BattleServer::with_interceptor(battle, jwt_interceptor))
+            .add_service(ContentServer::with_interceptor(content, jwt_interceptor))
             .serve_with_incoming(futures::stream::iter(vec![Ok::<_, std::io::Error>(server)]))
             .await
     });
@@ -0,0 +1,575 @@
+mod common;
+
+use animal_combat_grpc::{
+    content::ContentStore,
+    run_matchmaking_loop,
+    services::{
+        auth::{auth_client::AuthClient, JwtPair, LoginRequest},
+        battle::{
+            battle_client::BattleClient, battle_command::Command, client_battle_message::Message,
+            BattleState, ChallengeAnswer, ChallengeRequest, ClientBattleMessage, MatchEndReason,
+            Ready, ReplayRequest, SetBattleState, SpectateRequest, SurrenderBattle,
+        },
+        clans::{clan_client::ClanClient, ClanId, ClanInfo, ClanType, FriendlyBattleId},
+        players::{player_client::PlayerClient, Pagination},
+    },
+    ActivePlayers, BattleMessage, MatchmakerMessage, MatchmakerUpdate, Outbound,
+};
+use skillratings::sticko::StickoRating;
+use sqlx::PgPool;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request, Status};
+
+use crate::common::get_test_channel;
+
+async fn create_user(pool: &PgPool, email: String) -> Result<JwtPair, Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    let mut client = AuthClient::new(channel);
+
+    //Create test user
+    let user_credentials = LoginRequest {
+        email,
+        password: "TestPass".to_string(),
+    };
+    let request = Request::new(user_credentials.clone());
+
+    Ok(client.sign_up(request).await?.into_inner())
+}
+
+fn set_state(state: BattleState) -> Command {
+    Command::SetState(SetBattleState {
+        state: state.into(),
+    })
+}
+
+#[tokio::test]
+async fn test_outbound_receivers() {
+    let outbound = Outbound::default();
+    let mut player1 = outbound.register(1);
+    let mut player2 = outbound.register(2);
+
+    outbound.send(&[1], Ok(set_state(BattleState::GameStage)));
+    outbound.send(&[1, 2], Ok(set_state(BattleState::Finished)));
+
+    assert_eq!(
+        player1.recv().await.unwrap().unwrap().command,
+        Some(set_state(BattleState::GameStage))
+    );
+    assert_eq!(
+        player1.recv().await.unwrap().unwrap().command,
+        Some(set_state(BattleState::Finished))
+    );
+    assert_eq!(
+        player2.recv().await.unwrap().unwrap().command,
+        Some(set_state(BattleState::Finished))
+    );
+    assert!(player2.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_outbound_full_stream() {
+    let outbound = Outbound::default();
+    let mut slow = outbound.register(1);
+    let mut fast = outbound.register(2);
+
+    //The slow player never reads, so their stream is closed after the buffer is full
+    for _ in 0..1000 {
+        outbound.send(&[1, 2], Ok(set_state(BattleState::GameStage)));
+        fast.recv().await.unwrap().unwrap();
+    }
+    let mut received = 0;
+    while slow.recv().await.is_some() {
+        received += 1;
+    }
+    assert!(received < 1000);
+
+    //Reconnected player gets commands again
+    let mut slow = outbound.register(1);
+    outbound.send(&[1], Ok(set_state(BattleState::Finished)));
+    assert!(slow.recv().await.is_some());
+}
+
+//Starts the matchmaking, players with the same rating are matched together
+async fn join_players(
+    players: &[(i32, f64)],
+) -> (
+    mpsc::Sender<MatchmakerMessage>,
+    mpsc::Receiver<BattleMessage>,
+    Vec<mpsc::Receiver<MatchmakerUpdate>>,
+) {
+    let (tx, rx) = mpsc::channel(128);
+    let (battle_tx, battle_rx) = mpsc::channel(128);
+    tokio::spawn(run_matchmaking_loop(
+        rx,
+        battle_tx,
+        Outbound::default(),
+        ContentStore::default(),
+        ActivePlayers::default(),
+    ));
+
+    let mut updates = Vec::new();
+    for &(id, rating) in players {
+        let (updates_tx, updates_rx) = mpsc::channel(16);
+        tx.send(MatchmakerMessage::Subscribe { id, tx: updates_tx })
+            .await
+            .ok();
+        tx.send(MatchmakerMessage::JoinMatchmaking {
+            id,
+            rating: StickoRating {
+                rating,
+                ..Default::default()
+            },
+        })
+        .await
+        .ok();
+        updates.push(updates_rx);
+    }
+    (tx, battle_rx, updates)
+}
+
+//Skips queue statuses
+async fn next_update(updates: &mut mpsc::Receiver<MatchmakerUpdate>) -> MatchmakerUpdate {
+    loop {
+        match updates.recv().await.unwrap() {
+            MatchmakerUpdate::Status(_) => continue,
+            update => return update,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_matchmaking_notifications() {
+    let (tx, mut battle_rx, mut updates) =
+        join_players(&[(1, 1500f64), (2, 1500f64), (3, 3000f64)]).await;
+
+    //Matched players get only their own match
+    for updates in &mut updates[..2] {
+        assert!(matches!(
+            next_update(updates).await,
+            MatchmakerUpdate::MatchFound { .. }
+        ));
+    }
+
+    //The last player keeps waiting alone
+    let MatchmakerUpdate::Status(status) = updates[2].recv().await.unwrap() else {
+        panic!("Player 3 should not be matched");
+    };
+    assert_eq!(status.position, 1);
+    assert!(status.search_range > 0);
+    assert!(status.penalty_until.is_none());
+
+    //The battle is created only after both players accept
+    tx.send(MatchmakerMessage::AcceptMatch { id: 1 }).await.ok();
+    assert!(battle_rx.try_recv().is_err());
+    tx.send(MatchmakerMessage::AcceptMatch { id: 2 }).await.ok();
+    for updates in &mut updates[..2] {
+        assert!(matches!(
+            next_update(updates).await,
+            MatchmakerUpdate::MatchStarted
+        ));
+    }
+    assert!(matches!(
+        battle_rx.recv().await,
+        Some(BattleMessage::CreateBattle(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_matchmaking_decline() {
+    let (tx, mut battle_rx, mut updates) = join_players(&[(1, 1500f64), (2, 1500f64)]).await;
+    for updates in &mut updates {
+        next_update(updates).await;
+    }
+
+    tx.send(MatchmakerMessage::AcceptMatch { id: 1 }).await.ok();
+    tx.send(MatchmakerMessage::DeclineMatch { id: 2 })
+        .await
+        .ok();
+    assert!(matches!(
+        next_update(&mut updates[0]).await,
+        MatchmakerUpdate::MatchCancelled { requeued: true }
+    ));
+    assert!(matches!(
+        next_update(&mut updates[1]).await,
+        MatchmakerUpdate::MatchCancelled { requeued: false }
+    ));
+
+    //The decliner is not matched again for a while
+    tx.send(MatchmakerMessage::JoinMatchmaking {
+        id: 2,
+        rating: StickoRating::new(),
+    })
+    .await
+    .ok();
+    let status = loop {
+        if let MatchmakerUpdate::Status(status) = updates[1].recv().await.unwrap() {
+            break status;
+        }
+    };
+    assert!(status.penalty_until.is_some());
+    assert!(battle_rx.try_recv().is_err());
+}
+
+//Players who wait for a challenge, outside of the queue
+async fn subscribe_players(
+    ids: &[i32],
+) -> (
+    mpsc::Sender<MatchmakerMessage>,
+    mpsc::Receiver<BattleMessage>,
+    Vec<mpsc::Receiver<MatchmakerUpdate>>,
+) {
+    let (tx, battle_rx, _) = join_players(&[]).await;
+    let mut updates = Vec::new();
+    for &id in ids {
+        let (updates_tx, updates_rx) = mpsc::channel(16);
+        tx.send(MatchmakerMessage::Subscribe { id, tx: updates_tx })
+            .await
+            .ok();
+        updates.push(updates_rx);
+    }
+    (tx, battle_rx, updates)
+}
+
+async fn challenge(
+    tx: &mpsc::Sender<MatchmakerMessage>,
+    message: impl FnOnce(oneshot::Sender<Result<(), Status>>) -> MatchmakerMessage,
+) -> Result<(), Status> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send(message(reply_tx)).await.ok();
+    reply_rx.await.unwrap()
+}
+
+#[tokio::test]
+async fn test_challenge_accepted() {
+    let (tx, mut battle_rx, mut updates) = subscribe_players(&[1, 2]).await;
+    challenge(&tx, |tx| MatchmakerMessage::ChallengePlayer {
+        id: 1,
+        player_id: 2,
+        ranked: false,
+        tx,
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        next_update(&mut updates[1]).await,
+        MatchmakerUpdate::ChallengeReceived {
+            challenger_id: 1,
+            ranked: false,
+            ..
+        }
+    ));
+
+    challenge(&tx, |tx| MatchmakerMessage::AcceptChallenge {
+        id: 2,
+        challenger_id: 1,
+        tx,
+    })
+    .await
+    .unwrap();
+    let Some(BattleMessage::CreateBattle(m)) = battle_rx.recv().await else {
+        panic!("The battle should be created");
+    };
+    assert_eq!(m.players(), (1, 2));
+    assert!(!m.is_ranked());
+    for updates in &mut updates {
+        assert!(matches!(
+            next_update(updates).await,
+            MatchmakerUpdate::MatchFound { .. }
+        ));
+        assert!(matches!(
+            next_update(updates).await,
+            MatchmakerUpdate::MatchStarted
+        ));
+    }
+
+    //The challenge is gone
+    let res = challenge(&tx, |tx| MatchmakerMessage::AcceptChallenge {
+        id: 2,
+        challenger_id: 1,
+        tx,
+    })
+    .await;
+    assert_eq!(res.unwrap_err().code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn test_challenge_declined() {
+    let (tx, mut battle_rx, mut updates) = subscribe_players(&[1, 2]).await;
+    let send_challenge = |tx| MatchmakerMessage::ChallengePlayer {
+        id: 1,
+        player_id: 2,
+        ranked: true,
+        tx,
+    };
+    challenge(&tx, send_challenge).await.unwrap();
+    let res = challenge(&tx, send_challenge).await;
+    assert_eq!(res.unwrap_err().code(), Code::AlreadyExists);
+
+    challenge(&tx, |tx| MatchmakerMessage::DeclineChallenge {
+        id: 2,
+        challenger_id: 1,
+        tx,
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        next_update(&mut updates[0]).await,
+        MatchmakerUpdate::ChallengeDeclined { player_id: 2 }
+    ));
+    assert!(battle_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_challenge_player_in_battle() {
+    let (tx, mut battle_rx, _updates) = subscribe_players(&[1, 2, 3]).await;
+    for (id, player_id) in [(2, 1), (3, 1)] {
+        challenge(&tx, |tx| MatchmakerMessage::ChallengePlayer {
+            id,
+            player_id,
+            ranked: false,
+            tx,
+        })
+        .await
+        .unwrap();
+    }
+    challenge(&tx, |tx| MatchmakerMessage::AcceptChallenge {
+        id: 1,
+        challenger_id: 2,
+        tx,
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        battle_rx.recv().await,
+        Some(BattleMessage::CreateBattle(_))
+    ));
+
+    //The battle of player 1 is not over yet
+    let res = challenge(&tx, |tx| MatchmakerMessage::AcceptChallenge {
+        id: 1,
+        challenger_id: 3,
+        tx,
+    })
+    .await;
+    assert_eq!(res.unwrap_err().code(), Code::FailedPrecondition);
+    assert!(battle_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_matchmaking_leave_pending() {
+    let (tx, mut battle_rx, mut updates) = join_players(&[(1, 1500f64), (2, 1500f64)]).await;
+    for updates in &mut updates {
+        next_update(updates).await;
+    }
+
+    tx.send(MatchmakerMessage::AcceptMatch { id: 1 }).await.ok();
+    tx.send(MatchmakerMessage::LeaveMatchmaking { id: 1 })
+        .await
+        .ok();
+    assert!(matches!(
+        next_update(&mut updates[1]).await,
+        MatchmakerUpdate::MatchCancelled { requeued: true }
+    ));
+
+    //The player who left is not in the queue anymore
+    let status = loop {
+        if let MatchmakerUpdate::Status(status) = updates[1].recv().await.unwrap() {
+            break status;
+        }
+    };
+    assert_eq!(status.position, 1);
+    assert!(status.penalty_until.is_none());
+    assert!(battle_rx.try_recv().is_err());
+}
+
+#[sqlx::test]
+async fn test_spectate_battle(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    let mut clients = Vec::new();
+    let mut battle_clients = Vec::new();
+    for email in ["test@gmail.com", "test2@gmail.com", "test3@gmail.com"] {
+        let token = create_user(&pool, email.to_owned()).await?.access_token;
+        let token2 = token.clone();
+        clients.push(ClanClient::with_interceptor(
+            channel.clone(),
+            move |mut req: Request<()>| {
+                req.metadata_mut()
+                    .insert("authorization", token.parse().unwrap());
+                Ok(req)
+            },
+        ));
+        battle_clients.push(BattleClient::with_interceptor(
+            channel.clone(),
+            move |mut req: Request<()>| {
+                req.metadata_mut()
+                    .insert("authorization", token2.parse().unwrap());
+                Ok(req)
+            },
+        ));
+    }
+    let (player1, player2): (i32, i32) = sqlx::query_as("SELECT MIN(id), MIN(id) + 1 FROM players")
+        .fetch_one(&pool)
+        .await?;
+
+    sqlx::query("UPDATE players SET coins = 1000")
+        .execute(&pool)
+        .await?;
+    clients[0]
+        .create_clan(Request::new(ClanInfo {
+            name: "Test".to_owned(),
+            description: None,
+            min_glory: 0,
+            clan_type: ClanType::Open.into(),
+        }))
+        .await?;
+    clients[1].join_clan(Request::new(ClanId { id: 1 })).await?;
+    let id = clients[0]
+        .offer_friendly_battle(Request::new(()))
+        .await?
+        .into_inner()
+        .id;
+    clients[1]
+        .accept_friendly_battle(Request::new(FriendlyBattleId { id }))
+        .await?;
+
+    //Only clanmates can watch
+    let request = SpectateRequest {
+        player_id: player1,
+        delay_seconds: 0,
+    };
+    assert!(
+        battle_clients[2]
+            .spectate_battle(Request::new(request.clone()))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::PermissionDenied
+    );
+    clients[2].join_clan(Request::new(ClanId { id: 1 })).await?;
+    let mut commands = battle_clients[2]
+        .spectate_battle(Request::new(request))
+        .await?
+        .into_inner();
+    let Some(Command::Snapshot(snapshot)) = commands.message().await?.unwrap().command else {
+        panic!("The snapshot is sent first");
+    };
+    assert_eq!(snapshot.state(), BattleState::PickStage);
+    assert_eq!(snapshot.opponent_id, player2);
+    assert!(snapshot.animals.is_empty());
+
+    //Spectators see the same commands as the players
+    let mut senders = Vec::new();
+    for client in &mut battle_clients[..2] {
+        let (tx, rx) = mpsc::channel(16);
+        tx.send(ClientBattleMessage {
+            message: Some(Message::Ready(Ready {})),
+        })
+        .await?;
+        client.battle_messages(ReceiverStream::new(rx)).await?;
+        senders.push(tx);
+    }
+    let Some(Command::TurnToPick(turn)) = commands.message().await?.unwrap().command else {
+        panic!("The battle is started");
+    };
+    assert!(turn.player_id.is_some());
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_get_replay(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    let mut tokens = Vec::new();
+    for email in ["test@gmail.com", "test2@gmail.com"] {
+        tokens.push(create_user(&pool, email.to_owned()).await?.access_token);
+    }
+    let mut players: Vec<_> = tokens
+        .iter()
+        .cloned()
+        .map(|token| {
+            BattleClient::with_interceptor(channel.clone(), move |mut req: Request<()>| {
+                req.metadata_mut()
+                    .insert("authorization", token.parse().unwrap());
+                Ok(req)
+            })
+        })
+        .collect();
+    let (player1, player2): (i32, i32) = sqlx::query_as("SELECT MIN(id), MAX(id) FROM players")
+        .fetch_one(&pool)
+        .await?;
+    players[0]
+        .challenge_player(Request::new(ChallengeRequest {
+            player_id: player2,
+            unranked: true,
+        }))
+        .await?;
+    players[1]
+        .accept_challenge(Request::new(ChallengeAnswer {
+            challenger_id: player1,
+        }))
+        .await?;
+
+    let (tx, rx) = mpsc::channel(16);
+    tx.send(ClientBattleMessage {
+        message: Some(Message::Surrender(SurrenderBattle {})),
+    })
+    .await?;
+    let mut commands = players[0]
+        .battle_messages(ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+    //The match is saved before the result is sent
+    while !matches!(
+        commands.message().await?.unwrap().command,
+        Some(Command::Ended(_))
+    ) {}
+
+    let token = tokens[0].clone();
+    let mut client = PlayerClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut()
+            .insert("authorization", token.parse().unwrap());
+        Ok(req)
+    });
+    let id = client
+        .get_match_history(Request::new(Pagination {
+            offset: None,
+            limit: 10,
+        }))
+        .await?
+        .into_inner()
+        .matches[0]
+        .id;
+    let replay = players[1]
+        .get_replay(Request::new(ReplayRequest { match_id: id }))
+        .await?
+        .into_inner();
+    assert_eq!(
+        (replay.match_id, replay.player1_id, replay.player2_id),
+        (id, player1, player2)
+    );
+    let Some(Command::Ended(ended)) = replay
+        .commands
+        .last()
+        .unwrap()
+        .clone()
+        .command
+        .unwrap()
+        .command
+    else {
+        panic!("The replay ends with the result");
+    };
+    assert_eq!(ended.winner_id, Some(player2));
+    assert_eq!(ended.reason(), MatchEndReason::Surrender);
+
+    assert!(
+        players[1]
+            .get_replay(Request::new(ReplayRequest { match_id: id + 1 }))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::NotFound
+    );
+    Ok(())
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use animal_combat_grpc::{
+    content::ContentStore,
+    rules::BattleRules,
+    run_battles_loop, run_matchmaking_loop,
+    services::{
+        auth::{auth_client::AuthClient, LoginRequest},
+        battle::{battle_command::Command, TurnToPick},
+    },
+    ActivePlayers, BattleMessage, MatchmakerMessage, MatchmakerUpdate, Outbound,
+};
+use skillratings::sticko::StickoRating;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tonic::Request;
+
+use crate::common::get_test_channel;
+
+//The player only ends their turns, so the bot has to win
+#[sqlx::test]
+async fn test_bot_battle(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    AuthClient::new(channel)
+        .sign_up(Request::new(LoginRequest {
+            email: "test@gmail.com".to_owned(),
+            password: "TestPass".to_string(),
+        }))
+        .await?;
+    let (player_id, glory): (i32, i32) = sqlx::query_as("SELECT id, glory FROM players")
+        .fetch_one(&pool)
+        .await?;
+
+    //The bot is chosen right away
+    let rules = BattleRules {
+        bot_wait_time: 0,
+        ..BattleRules::load()
+    };
+    let path = std::env::temp_dir().join("test_bot_battle_rules.json");
+    std::fs::write(&path, serde_json::to_string(&rules)?)?;
+    std::env::set_var("BATTLE_RULES", &path);
+    let (tx, rx) = mpsc::channel(128);
+    let (battle_tx, battle_rx) = mpsc::channel(128);
+    let outbound = Outbound::default();
+    let active = ActivePlayers::default();
+    tokio::spawn(run_matchmaking_loop(
+        rx,
+        battle_tx.clone(),
+        outbound.clone(),
+        ContentStore::default(),
+        active.clone(),
+    ));
+    tokio::spawn(run_battles_loop(
+        battle_rx,
+        outbound.clone(),
+        active,
+        pool.clone(),
+    ));
+
+    let (updates_tx, mut updates) = mpsc::channel(16);
+    tx.send(MatchmakerMessage::Subscribe {
+        id: player_id,
+        tx: updates_tx,
+    })
+    .await
+    .ok();
+    tx.send(MatchmakerMessage::JoinMatchmaking {
+        id: player_id,
+        rating: StickoRating::new(),
+    })
+    .await
+    .ok();
+    let bot_id = loop {
+        if let MatchmakerUpdate::MatchFound { m, .. } = updates.recv().await.unwrap() {
+            assert!(!m.is_ranked());
+            break m.players().1;
+        }
+    };
+    assert!(bot_id < 0);
+
+    let mut stream = outbound.register(player_id);
+    tx.send(MatchmakerMessage::AcceptMatch { id: player_id })
+        .await
+        .ok();
+    while !matches!(updates.recv().await, Some(MatchmakerUpdate::MatchStarted)) {}
+    for message in [
+        BattleMessage::Connected { player_id },
+        BattleMessage::Ready { player_id },
+    ] {
+        battle_tx.send(message).await.ok();
+    }
+    let ended = loop {
+        //Ending the turn out of the game stage is an error, it is ignored
+        match stream.recv().await.unwrap().map(|f| f.command) {
+            Ok(Some(Command::TurnToPick(TurnToPick {
+                player_id: Some(turn),
+                ..
+            }))) if turn == player_id => {
+                battle_tx
+                    .send(BattleMessage::EndTurn { player_id })
+                    .await
+                    .ok();
+            }
+            Ok(Some(Command::Ended(ended))) => break ended,
+            _ => {}
+        }
+    };
+    assert_eq!(ended.winner_id, Some(bot_id), "{ended:?}");
+    assert_eq!((ended.glory, ended.glory_delta), (glory, 0));
+
+    //Bot battles are neither rated nor stored
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM matches")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(count, 0);
+    Ok(())
+}
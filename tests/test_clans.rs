@@ -3,8 +3,8 @@ mod common;
 use animal_combat_grpc::services::{
     auth::{auth_client::AuthClient, JwtPair, LoginRequest},
     clans::{
-        clan_client::ClanClient, ClanId, ClanInfo, ClanType, Pagination, SearchClansRequest,
-        TextMessage,
+        clan_client::ClanClient, ClanId, ClanInfo, ClanType, FriendlyBattleId, MessageType,
+        Pagination, SearchClansRequest, TextMessage,
     },
 };
 use sqlx::PgPool;
@@ -686,3 +686,111 @@ async fn test_get_messages(pool: PgPool) -> Result<(), Box<dyn std::error::Error
 
     Ok(())
 }
+
+#[sqlx::test]
+async fn test_friendly_battle(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    //Both players use the same server, so they see the same offers
+    let channel = get_test_channel(pool.clone()).await?;
+    let user_response = create_user(&pool, "test@gmail.com".to_owned()).await?;
+    let mut client = ClanClient::with_interceptor(channel.clone(), move |mut req: Request<()>| {
+        req.metadata_mut()
+            .insert("authorization", user_response.access_token.parse().unwrap());
+        Ok(req)
+    });
+    let user_response = create_user(&pool, "test2@gmail.com".to_owned()).await?;
+    let mut client2 = ClanClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut()
+            .insert("authorization", user_response.access_token.parse().unwrap());
+        Ok(req)
+    });
+
+    //Only clan members can offer battles
+    assert!(
+        client
+            .offer_friendly_battle(Request::new(()))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::PermissionDenied
+    );
+
+    sqlx::query("UPDATE players SET coins = 1000")
+        .execute(&pool)
+        .await?;
+    let request = Request::new(ClanInfo {
+        name: "Test".to_owned(),
+        description: None,
+        min_glory: 0,
+        clan_type: ClanType::Open.into(),
+    });
+    client.create_clan(request).await?;
+    client2.join_clan(Request::new(ClanId { id: 1 })).await?;
+    let mut messages = client.receive_message(Request::new(())).await?.into_inner();
+
+    let id = client
+        .offer_friendly_battle(Request::new(()))
+        .await?
+        .into_inner()
+        .id;
+    let offer = messages.message().await?.unwrap();
+    assert_eq!(offer.message_type, MessageType::SystemPositive as i32);
+    assert_eq!(offer.friendly_battle_id, Some(id));
+
+    //The offer is for other members
+    assert!(
+        client
+            .accept_friendly_battle(Request::new(FriendlyBattleId { id }))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::PermissionDenied
+    );
+    assert!(
+        client2
+            .accept_friendly_battle(Request::new(FriendlyBattleId { id: id + 1 }))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::NotFound
+    );
+
+    client2
+        .accept_friendly_battle(Request::new(FriendlyBattleId { id }))
+        .await?;
+    let taken = messages.message().await?.unwrap();
+    assert_eq!(taken.friendly_battle_id, Some(id));
+
+    //Somebody was faster
+    assert!(
+        client2
+            .accept_friendly_battle(Request::new(FriendlyBattleId { id }))
+            .await
+            .err()
+            .unwrap()
+            .code()
+            == Code::NotFound
+    );
+
+    //Both players are still battling, so the new offer waits for someone else
+    let id = client
+        .offer_friendly_battle(Request::new(()))
+        .await?
+        .into_inner()
+        .id;
+    messages.message().await?.unwrap();
+    for _ in 0..2 {
+        assert!(
+            client2
+                .accept_friendly_battle(Request::new(FriendlyBattleId { id }))
+                .await
+                .err()
+                .unwrap()
+                .code()
+                == Code::FailedPrecondition
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use std::path::PathBuf;
+
+use animal_combat_grpc::{
+    content::{ContentStore, GameContent},
+    rules::BattleRules,
+    services::{
+        auth::{auth_client::AuthClient, LoginRequest},
+        content::{content_client::ContentClient, AbilityType},
+    },
+};
+use serde_json::Value;
+use sqlx::PgPool;
+use tonic::Request;
+
+use crate::common::get_test_channel;
+
+//Directory with the bundled content, which the test can change
+fn content_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("data/animals.json", dir.join("animals.json")).unwrap();
+    std::fs::copy("data/maps.json", dir.join("maps.json")).unwrap();
+    dir
+}
+
+fn animal_count(content: &GameContent) -> usize {
+    let animals = serde_json::to_value(content.animals.as_ref()).unwrap();
+    animals["animals"].as_array().unwrap().len()
+}
+
+fn first_animal_hp(content: &GameContent) -> i64 {
+    let animals = serde_json::to_value(content.animals.as_ref()).unwrap();
+    animals["animals"][0]["hp"].as_i64().unwrap()
+}
+
+#[test]
+fn test_reload_keeps_old_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = content_dir("test_reload_keeps_old_snapshot");
+    let store = ContentStore::from_dir(&dir, BattleRules::load())?;
+    let old = store.current();
+    let hp = first_animal_hp(&old);
+
+    let path = dir.join("animals.json");
+    let mut animals: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    animals["animals"][0]["hp"] = (hp + 10).into();
+    std::fs::write(&path, animals.to_string())?;
+    store.reload()?;
+
+    //Running battles keep their animals, new ones get the changed content
+    assert_eq!(first_animal_hp(&old), hp);
+    assert_eq!(first_animal_hp(&store.current()), hp + 10);
+    assert_ne!(old.version, store.current().version);
+
+    //Invalid content does not replace the snapshot
+    let changed = store.current();
+    animals["animals"][0]["hp"] = 0.into();
+    std::fs::write(&path, animals.to_string())?;
+    assert!(store.reload().is_err());
+    assert_eq!(first_animal_hp(&store.current()), hp + 10);
+    assert_eq!(store.current().version, changed.version);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_reload_is_ignored() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = content_dir("test_invalid_reload_is_ignored");
+    let store = ContentStore::from_dir(&dir, BattleRules::load())?;
+    let count = animal_count(&store.current());
+
+    std::fs::write(dir.join("animals.json"), "{\"animals\": []}")?;
+    assert!(store.reload().is_err());
+    assert_eq!(animal_count(&store.current()), count);
+
+    std::fs::write(dir.join("maps.json"), "not json")?;
+    assert!(ContentStore::from_dir(&dir, BattleRules::load()).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_validate_reports_every_error() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = content_dir("test_validate_reports_every_error");
+    let path = dir.join("animals.json");
+    let mut animals: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    animals["animals"][1]["id"] = animals["animals"][0]["id"].clone();
+    animals["animals"][0]["abilities"][0]
+        .as_object_mut()
+        .unwrap()
+        .remove("cost");
+    std::fs::write(&path, animals.to_string())?;
+    let path = dir.join("maps.json");
+    let mut maps: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    let objects = maps["maps"][0]["objects"].as_array_mut().unwrap();
+    objects.push(objects[0].clone());
+    objects.push(serde_json::json!({"x": 7, "y": 0, "object_type": "solid"}));
+    std::fs::write(&path, maps.to_string())?;
+
+    let rules = BattleRules::load();
+    let content = GameContent::read(&dir)?;
+    let errors = content
+        .errors(&rules)
+        .into_iter()
+        .map(|f| f.path)
+        .collect::<Vec<String>>();
+    let count = maps["maps"][0]["objects"].as_array().unwrap().len();
+    assert_eq!(
+        errors,
+        [
+            "animals.json: animals[0].abilities[0].cost".to_string(),
+            "animals.json: animals[1].id".to_string(),
+            format!("maps.json: maps[0].objects[{}]", count - 2),
+            format!("maps.json: maps[0].objects[{}]", count - 1),
+        ]
+    );
+    assert!(content.validate(&rules).is_err());
+    assert!(ContentStore::from_dir(&dir, rules).is_err());
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_content_catalog(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    let user = AuthClient::new(channel.clone())
+        .sign_up(Request::new(LoginRequest {
+            email: "test@gmail.com".to_string(),
+            password: "TestPass".to_string(),
+        }))
+        .await?
+        .into_inner();
+    let mut client = ContentClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut()
+            .insert("authorization", user.access_token.parse().unwrap());
+        Ok(req)
+    });
+
+    let content = GameContent::bundled();
+    let animals = client.list_animals(Request::new(())).await?.into_inner();
+    assert_eq!(animals.version, content.version);
+    assert_eq!(animals.animals.len(), animal_count(&content));
+    let cat = animals.animals.iter().find(|f| f.name == "Cat").unwrap();
+    let pounce = cat.abilities.iter().find(|f| f.name == "Pounce").unwrap();
+    assert_eq!(pounce.r#type(), AbilityType::Active);
+    assert_eq!((pounce.cost, pounce.cooldown), (Some(30), Some(3)));
+
+    let maps = client.list_maps(Request::new(())).await?.into_inner();
+    assert_eq!(maps.version, content.version);
+    assert!(!maps.maps.is_empty());
+
+    let version = client
+        .get_content_version(Request::new(()))
+        .await?
+        .into_inner();
+    assert_eq!(version.version, content.version);
+    Ok(())
+}
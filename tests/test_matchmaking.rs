@@ -0,0 +1,248 @@
+use std::sync::{Arc, Mutex};
+
+use animal_combat_grpc::{
+    content::ContentStore,
+    matchmaking::{Clock, StickoStrategy},
+    rules::BattleRules,
+    ActivePlayers, Matchmaker,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::StdRng, SeedableRng};
+use skillratings::sticko::StickoRating;
+
+#[derive(Clone)]
+struct TestClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl TestClock {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Utc::now())))
+    }
+
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn matchmaker(clock: &TestClock, seed: u64) -> Matchmaker {
+    Matchmaker::new(
+        Box::<StickoStrategy>::default(),
+        Box::new(clock.clone()),
+        Box::new(StdRng::seed_from_u64(seed)),
+    )
+}
+
+fn rating(rating: f64, deviation: f64) -> StickoRating {
+    StickoRating { rating, deviation }
+}
+
+fn pairs(matchmaker: &mut Matchmaker) -> Vec<(i32, i32)> {
+    matchmaker
+        .find_matches()
+        .iter()
+        .map(|f| f.0.players())
+        .collect()
+}
+
+//Both players accept every match and play it to the end
+fn play(matchmaker: &mut Matchmaker, active: &ActivePlayers, matches: &[(i32, i32)]) {
+    for (player1, player2) in matches {
+        matchmaker.accept_match(*player1);
+        assert!(matchmaker.accept_match(*player2).is_some());
+        active.remove(&[*player1, *player2]);
+    }
+}
+
+#[test]
+fn test_fairest_opponent() {
+    let clock = TestClock::new();
+    let mut matchmaker = matchmaker(&clock, 0);
+    matchmaker.add_player(1, rating(1500f64, 50f64));
+    matchmaker.add_player(2, rating(1560f64, 50f64));
+    matchmaker.add_player(3, rating(1520f64, 50f64));
+
+    assert_eq!(pairs(&mut matchmaker), vec![(1, 3)]);
+    assert!(pairs(&mut matchmaker).is_empty());
+}
+
+#[test]
+fn test_uncertain_ratings() {
+    let clock = TestClock::new();
+    let mut matchmaker = matchmaker(&clock, 0);
+
+    //Settled ratings are too far apart
+    matchmaker.add_player(1, rating(1500f64, 50f64));
+    matchmaker.add_player(2, rating(1600f64, 50f64));
+    assert!(pairs(&mut matchmaker).is_empty());
+    matchmaker.remove_player(1);
+    matchmaker.remove_player(2);
+
+    //Nobody knows how good new players are
+    matchmaker.add_player(3, rating(1500f64, 350f64));
+    matchmaker.add_player(4, rating(1600f64, 350f64));
+    assert_eq!(pairs(&mut matchmaker), vec![(3, 4)]);
+}
+
+#[test]
+fn test_waiting_widens_search() {
+    let clock = TestClock::new();
+    let mut matchmaker = matchmaker(&clock, 0);
+    matchmaker.add_player(1, rating(1500f64, 50f64));
+    matchmaker.add_player(2, rating(1600f64, 50f64));
+    assert!(pairs(&mut matchmaker).is_empty());
+
+    clock.advance(Duration::seconds(10));
+    assert_eq!(pairs(&mut matchmaker), vec![(1, 2)]);
+}
+
+#[test]
+fn test_no_immediate_rematch() {
+    let clock = TestClock::new();
+    let active = ActivePlayers::default();
+    let mut matchmaker = matchmaker(&clock, 0).with_active_players(active.clone());
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(2, StickoRating::new());
+    let matches = pairs(&mut matchmaker);
+    assert_eq!(matches, vec![(1, 2)]);
+    play(&mut matchmaker, &active, &matches);
+
+    //They come back after the battle
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(2, StickoRating::new());
+    assert!(pairs(&mut matchmaker).is_empty());
+
+    clock.advance(StickoStrategy::default().rematch_cooldown);
+    assert_eq!(pairs(&mut matchmaker), vec![(1, 2)]);
+}
+
+#[test]
+fn test_rematch_avoided_for_other_opponent() {
+    let clock = TestClock::new();
+    let active = ActivePlayers::default();
+    let mut matchmaker = matchmaker(&clock, 0).with_active_players(active.clone());
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(2, StickoRating::new());
+    let matches = pairs(&mut matchmaker);
+    assert_eq!(matches, vec![(1, 2)]);
+    play(&mut matchmaker, &active, &matches);
+
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(2, StickoRating::new());
+    matchmaker.add_player(3, StickoRating::new());
+    assert_eq!(pairs(&mut matchmaker), vec![(1, 3)]);
+}
+
+#[test]
+fn test_no_double_matching() {
+    let clock = TestClock::new();
+    let active = ActivePlayers::default();
+    let mut matchmaker = matchmaker(&clock, 0).with_active_players(active.clone());
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(2, StickoRating::new());
+    let matches = pairs(&mut matchmaker);
+    assert_eq!(matches, vec![(1, 2)]);
+
+    //Joining again while the match is proposed or played is ignored
+    matchmaker.add_player(1, StickoRating::new());
+    matchmaker.add_player(3, StickoRating::new());
+    assert!(pairs(&mut matchmaker).is_empty());
+    matchmaker.accept_match(1);
+    matchmaker.accept_match(2);
+    matchmaker.add_player(1, StickoRating::new());
+    assert!(pairs(&mut matchmaker).is_empty());
+
+    active.remove(&[1, 2]);
+    matchmaker.add_player(1, StickoRating::new());
+    assert_eq!(pairs(&mut matchmaker), vec![(3, 1)]);
+}
+
+#[test]
+fn test_seeded_matches() {
+    let clock = TestClock::new();
+    let run = |seed: u64| {
+        let mut matchmaker = matchmaker(&clock, seed);
+        for id in 1..=8 {
+            matchmaker.add_player(id, StickoRating::new());
+        }
+        pairs(&mut matchmaker)
+    };
+    let matches = run(42);
+    assert_eq!(matches.len(), 4);
+    assert_eq!(matches, run(42));
+    assert!((0..16).any(|seed| run(seed) != matches));
+}
+
+#[test]
+fn test_bot_after_waiting() {
+    let clock = TestClock::new();
+    let mut matchmaker = matchmaker(&clock, 0).with_bot_wait(Some(Duration::seconds(30)));
+    matchmaker.add_player(1, rating(1500f64, 50f64));
+    matchmaker.add_player(2, rating(2500f64, 50f64));
+    assert!(pairs(&mut matchmaker).is_empty());
+
+    clock.advance(Duration::seconds(30));
+    let matches = matchmaker.find_matches();
+    assert_eq!(matches.len(), 2);
+    for (m, _) in &matches {
+        assert!(m.players().1 < 0);
+        assert!(!m.is_ranked());
+    }
+    assert_ne!(matches[0].0.players().1, matches[1].0.players().1);
+}
+
+//Content with empty maps of the given names and weights
+fn maps_content(name: &str, maps: &[(&str, u32)]) -> ContentStore {
+    let dir = std::env::temp_dir().join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("data/animals.json", dir.join("animals.json")).unwrap();
+    let maps = maps
+        .iter()
+        .map(|(name, weight)| {
+            serde_json::json!({
+                "map_name": name,
+                "width": 7,
+                "height": 24,
+                "player1_zone": { "start": 0, "end": 12 },
+                "player2_zone": { "start": 12, "end": 24 },
+                "weight": weight,
+                "objects": []
+            })
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(
+        dir.join("maps.json"),
+        serde_json::json!({ "maps": maps }).to_string(),
+    )
+    .unwrap();
+    ContentStore::from_dir(dir, BattleRules::load()).unwrap()
+}
+
+#[test]
+fn test_map_rotation() {
+    let clock = TestClock::new();
+    let active = ActivePlayers::default();
+    let mut matchmaker = matchmaker(&clock, 0)
+        .with_content(maps_content(
+            "test_map_rotation",
+            &[("Forest", 1), ("Lake", 1), ("Closed", 0)],
+        ))
+        .with_active_players(active.clone());
+
+    //Players do not get the same map twice in a row, closed maps are never chosen
+    let mut last = None;
+    for _ in 0..10 {
+        matchmaker.add_player(1, rating(1500f64, 50f64));
+        matchmaker.add_player(2, rating(1500f64, 50f64));
+        let (m, _) = matchmaker.find_matches().remove(0);
+        assert_ne!(m.map_name(), "Closed");
+        assert_ne!(Some(m.map_name().to_string()), last);
+        last = Some(m.map_name().to_string());
+        play(&mut matchmaker, &active, &[m.players()]);
+        clock.advance(StickoStrategy::default().rematch_cooldown);
+    }
+}
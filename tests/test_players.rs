@@ -2,7 +2,7 @@ mod common;
 
 use animal_combat_grpc::services::{
     auth::{auth_client::AuthClient, JwtPair, LoginRequest},
-    players::player_client::PlayerClient,
+    players::{player_client::PlayerClient, MatchOutcome, Pagination},
 };
 use sqlx::PgPool;
 use tonic::Request;
@@ -64,3 +64,79 @@ async fn test_get_emotes(pool: PgPool) -> Result<(), Box<dyn std::error::Error>>
 
     Ok(())
 }
+
+#[sqlx::test]
+async fn test_get_match_history(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = get_test_channel(pool.clone()).await?;
+    let user_response = create_user(&pool, "test@gmail.com".to_owned()).await?;
+    create_user(&pool, "test2@gmail.com".to_owned()).await?;
+
+    let (player1,): (i32,) = sqlx::query_as("SELECT id FROM players WHERE email = $1")
+        .bind("test@gmail.com")
+        .fetch_one(&pool)
+        .await?;
+    let (player2,): (i32,) = sqlx::query_as("SELECT id FROM players WHERE email = $1")
+        .bind("test2@gmail.com")
+        .fetch_one(&pool)
+        .await?;
+    for winner in [Some(player1), Some(player2), None] {
+        sqlx::query(
+            "INSERT INTO matches (player1_id, player2_id, map_name, player1_animals, player2_animals,
+                                  winner_id, end_reason, player1_glory_delta, player2_glory_delta,
+                                  started_at, finished_at)
+            VALUES ($1, $2, 'Map', '{1, 2, 3}', '{4, 5, 6}', $3, 'AllAnimalsDead', 10, -10,
+                    NOW() - INTERVAL '5 minutes', NOW())",
+        )
+        .bind(player2)
+        .bind(player1)
+        .bind(winner)
+        .execute(&pool)
+        .await?;
+    }
+
+    let mut client = PlayerClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut()
+            .insert("authorization", user_response.access_token.parse().unwrap());
+        Ok(req)
+    });
+
+    let history = client
+        .get_match_history(Request::new(Pagination {
+            offset: None,
+            limit: 2,
+        }))
+        .await?
+        .into_inner();
+    assert!(history.matches.len() == 2);
+    assert!(history.matches[0].opponent_id == player2);
+    assert!(history.matches[0].animals == vec![4, 5, 6]);
+    assert!(history.matches[0].glory_delta == -10);
+    assert!(history.matches[0].duration == 300);
+
+    let history = client
+        .get_match_history(Request::new(Pagination {
+            offset: Some(2),
+            limit: 2,
+        }))
+        .await?
+        .into_inner();
+    assert!(history.matches.len() == 1);
+    assert!(history.offset == 2);
+
+    let outcomes: Vec<MatchOutcome> = client
+        .get_match_history(Request::new(Pagination {
+            offset: None,
+            limit: 10,
+        }))
+        .await?
+        .into_inner()
+        .matches
+        .iter()
+        .map(|f| f.outcome())
+        .collect();
+    assert!(outcomes.contains(&MatchOutcome::Victory));
+    assert!(outcomes.contains(&MatchOutcome::Defeat));
+    assert!(outcomes.contains(&MatchOutcome::Draw));
+
+    Ok(())
+}
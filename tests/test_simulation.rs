@@ -0,0 +1,924 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use animal_combat_grpc::{
+    content::ContentStore,
+    matchmaking::{Clock, StickoStrategy},
+    rules::BattleRules,
+    services::battle::{
+        battle_command::Command, client_battle_message::Message, AbilityUsed, AnimalDamaged,
+        AnimalDead, AnimalHealed, AnimalMoved, AnimalPicked, BattleCommand, BattleState,
+        DamageAnimal, EffectApplied, EffectExpired, EffectType, EndTurn, GetBattleState,
+        MoveAnimal, ObjectRemoved, PickAnimal, PlaceAnimal, PlaceAnimals, Position, Ready,
+        SurrenderBattle, UseAbility, UseAnimal,
+    },
+    Battle, BattleMessage, Matchmaker, Outbound,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rand::{rngs::StdRng, SeedableRng};
+use skillratings::sticko::StickoRating;
+use tokio::sync::mpsc;
+use tonic::Status;
+
+const CAT: i32 = 1;
+const CHICK: i32 = 2;
+const FOX: i32 = 3;
+const MOUSE: i32 = 4;
+const PIG: i32 = 5;
+const RABBIT: i32 = 6;
+
+#[derive(Clone)]
+struct TestClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl TestClock {
+    //The same time in every test, so deadlines are the same too
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(
+            Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        )))
+    }
+
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+type Received = HashMap<i32, Vec<Result<Command, Status>>>;
+
+//Battle between players 1 and 2, driven by hand
+struct TestBattle {
+    battle: Battle,
+    clock: TestClock,
+    streams: Vec<(i32, mpsc::Receiver<Result<BattleCommand, Status>>)>,
+    //Player, who has to pick or act now
+    turn: Option<i32>,
+}
+
+impl TestBattle {
+    fn new(seed: u64) -> Self {
+        Self::with_rules(seed, BattleRules::load())
+    }
+
+    fn with_rules(seed: u64, rules: BattleRules) -> Self {
+        Self::with_content(seed, rules, ContentStore::default())
+    }
+
+    fn with_content(seed: u64, rules: BattleRules, content: ContentStore) -> Self {
+        let clock = TestClock::new();
+        let mut matchmaker = Matchmaker::new(
+            Box::<StickoStrategy>::default(),
+            Box::new(clock.clone()),
+            Box::new(StdRng::seed_from_u64(seed)),
+        )
+        .with_content(content);
+        matchmaker.add_player(1, StickoRating::new());
+        matchmaker.add_player(2, StickoRating::new());
+        let (m, _) = matchmaker.find_matches().remove(0);
+
+        let outbound = Outbound::default();
+        let streams = [1, 2]
+            .into_iter()
+            .map(|id| (id, outbound.register(id)))
+            .collect();
+        let battle = Battle::new(
+            m,
+            outbound,
+            rules,
+            StdRng::seed_from_u64(seed),
+            Box::new(clock.clone()),
+        );
+        Self {
+            battle,
+            clock,
+            streams,
+            turn: None,
+        }
+    }
+
+    //Runs the battle and returns, what every player received
+    fn run(&mut self, message: Option<BattleMessage>) -> Received {
+        self.battle.run(message);
+        let mut received = HashMap::new();
+        for (player_id, stream) in &mut self.streams {
+            let mut commands = Vec::new();
+            while let Ok(res) = stream.try_recv() {
+                let res = res.map(|f| f.command.unwrap());
+                if let Ok(Command::TurnToPick(turn)) = &res {
+                    self.turn = turn.player_id;
+                }
+                commands.push(res);
+            }
+            received.insert(*player_id, commands);
+        }
+        received
+    }
+
+    fn send(&mut self, player_id: i32, message: Message) -> Received {
+        self.run(Some(BattleMessage::from_client(player_id, message)))
+    }
+
+    fn tick(&mut self, seconds: i64) -> Received {
+        self.clock.advance(Duration::seconds(seconds));
+        self.run(None)
+    }
+
+    fn turn(&self) -> i32 {
+        self.turn.unwrap()
+    }
+
+    //Both players open their battle streams and are ready
+    fn start(&mut self) {
+        for player_id in [1, 2] {
+            self.run(Some(BattleMessage::Connected { player_id }));
+            self.send(player_id, Message::Ready(Ready {}));
+        }
+    }
+
+    //Every player picks their animals in order, when it is their turn
+    fn pick_animals(&mut self, animals: [&[i32]; 2]) -> Received {
+        let mut picked = [0, 0];
+        let mut received = HashMap::new();
+        while picked[0] < animals[0].len() || picked[1] < animals[1].len() {
+            let player_id = self.turn();
+            let index = (player_id - 1) as usize;
+            let animal_id = animals[index][picked[index]];
+            picked[index] += 1;
+            received = self.send(player_id, Message::Pick(PickAnimal { animal_id }));
+        }
+        received
+    }
+
+    //Positions are absolute, the second player sends them from their side of the board
+    fn place_animals(&mut self, player_id: i32, animals: [(i32, i32, i32); 3]) -> Received {
+        let animals = animals
+            .into_iter()
+            .map(|(animal_id, x, y)| PlaceAnimal {
+                animal_id,
+                position: Some(Position {
+                    x,
+                    y: if player_id == 2 { 23 - y } else { y },
+                }),
+            })
+            .collect();
+        self.send(player_id, Message::Place(PlaceAnimals { animals }))
+    }
+
+    //Places the animals, so the fox of the first player faces the chick of the second one
+    fn prepare(&mut self) {
+        self.face_off([[FOX, CAT, MOUSE], [CHICK, PIG, RABBIT]]);
+    }
+
+    //The first animals of the players face each other, the others stay far away
+    fn face_off(&mut self, [first, second]: [[i32; 3]; 2]) {
+        self.start();
+        self.pick_animals([&first, &second]);
+        self.place_animals(1, [(first[0], 0, 11), (first[1], 6, 0), (first[2], 5, 0)]);
+        self.place_animals(
+            2,
+            [(second[0], 0, 12), (second[1], 6, 23), (second[2], 5, 23)],
+        );
+    }
+
+    //Moves and attacks are flipped for the first player
+    fn position(player_id: i32, x: i32, y: i32) -> Option<Position> {
+        Some(Position {
+            x,
+            y: if player_id == 1 { 23 - y } else { y },
+        })
+    }
+
+    fn cast(&mut self, player_id: i32, ability: &str, position: Option<Position>) -> Received {
+        self.send(
+            player_id,
+            Message::Ability(UseAbility {
+                ability: ability.to_string(),
+                position,
+            }),
+        )
+    }
+
+    //Steps the animal has left, only its player sees them
+    fn mobility(&mut self, player_id: i32, animal_id: i32) -> i32 {
+        let received = self.send(player_id, Message::GetState(GetBattleState {}));
+        commands(&received, player_id)
+            .into_iter()
+            .find_map(|f| match f {
+                Command::Snapshot(snapshot) => {
+                    snapshot
+                        .animals
+                        .iter()
+                        .find(|f| f.animal_id == animal_id)?
+                        .mobility
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    //Gives the turn to the player
+    fn turn_of(&mut self, player_id: i32) {
+        if self.turn() != player_id {
+            self.send(self.turn(), Message::End(EndTurn {}));
+        }
+        assert_eq!(self.turn(), player_id);
+    }
+}
+
+fn commands(received: &Received, player_id: i32) -> Vec<&Command> {
+    received[&player_id]
+        .iter()
+        .filter_map(|f| f.as_ref().ok())
+        .collect()
+}
+
+fn has_error(received: &Received, player_id: i32) -> bool {
+    received[&player_id].iter().any(|f| f.is_err())
+}
+
+fn has_state(received: &Received, player_id: i32, state: BattleState) -> bool {
+    commands(received, player_id)
+        .iter()
+        .any(|f| matches!(f, Command::SetState(f) if f.state() == state))
+}
+
+fn ability_used(received: &Received, player_id: i32) -> Option<&AbilityUsed> {
+    commands(received, player_id)
+        .into_iter()
+        .find_map(|f| match f {
+            Command::AbilityUsed(used) => Some(used),
+            _ => None,
+        })
+}
+
+//Damage, that the animal deals with its hit
+fn hit(battle: &mut TestBattle, player_id: i32, x: i32, y: i32) -> i32 {
+    let received = battle.send(
+        player_id,
+        Message::Damage(DamageAnimal {
+            position: TestBattle::position(player_id, x, y),
+        }),
+    );
+    commands(&received, player_id)
+        .into_iter()
+        .find_map(|f| match f {
+            Command::Damaged(damaged) => Some(damaged.damage),
+            _ => None,
+        })
+        .unwrap()
+}
+
+//Timeouts pick and place random animals, the seed decides which ones
+#[test]
+fn test_same_seed_same_battle() {
+    let play = |seed: u64| {
+        let mut battle = TestBattle::new(seed);
+        battle.start();
+        let mut log = Vec::new();
+        for _ in 0..10 {
+            log.push(format!("{:?}", battle.tick(2)[&1]));
+        }
+        log
+    };
+    let log = play(7);
+    assert_eq!(log, play(7));
+    assert!(log.iter().any(|f| f.contains("GameStage")), "{log:?}");
+}
+
+#[test]
+fn test_pick() {
+    let mut battle = TestBattle::new(0);
+    battle.start();
+    let turn = battle.turn();
+    let other = 3 - turn;
+
+    let received = battle.send(other, Message::Pick(PickAnimal { animal_id: FOX }));
+    assert!(has_error(&received, other));
+
+    let received = battle.send(turn, Message::Pick(PickAnimal { animal_id: FOX }));
+    for player_id in [1, 2] {
+        assert!(
+            commands(&received, player_id).contains(&&Command::Picked(AnimalPicked {
+                player_id: turn,
+                animal_id: FOX,
+            }))
+        );
+    }
+
+    //Every animal is picked once
+    let received = battle.send(other, Message::Pick(PickAnimal { animal_id: FOX }));
+    assert!(has_error(&received, other));
+
+    //The player, who picked the fox, has two animals to pick
+    let mut animals: [&[i32]; 2] = [&[CHICK, RABBIT, PIG]; 2];
+    animals[(turn - 1) as usize] = &[CAT, MOUSE];
+    let received = battle.pick_animals(animals);
+    assert!(has_state(&received, 1, BattleState::PlacementStage));
+}
+
+#[test]
+fn test_placement() {
+    let mut battle = TestBattle::new(0);
+    battle.start();
+    battle.pick_animals([&[FOX, CAT, MOUSE], &[CHICK, PIG, RABBIT]]);
+
+    //The opponent's half is not allowed
+    let received = battle.place_animals(1, [(FOX, 0, 12), (CAT, 6, 0), (MOUSE, 5, 0)]);
+    assert!(has_error(&received, 1));
+
+    //Squares with map objects are taken
+    let received = battle.place_animals(1, [(FOX, 2, 10), (CAT, 6, 0), (MOUSE, 5, 0)]);
+    assert!(has_error(&received, 1));
+    //Only own animals are placed
+    let received = battle.place_animals(1, [(CHICK, 0, 11), (CAT, 6, 0), (MOUSE, 5, 0)]);
+    assert!(has_error(&received, 1));
+
+    let received = battle.place_animals(1, [(FOX, 0, 11), (CAT, 6, 0), (MOUSE, 5, 0)]);
+    assert!(!has_error(&received, 1));
+    assert!(!has_state(&received, 1, BattleState::GameStage));
+
+    let received = battle.place_animals(2, [(CHICK, 0, 12), (PIG, 6, 23), (RABBIT, 5, 23)]);
+    assert!(!has_error(&received, 2));
+    assert!(has_state(&received, 1, BattleState::GameStage));
+}
+
+#[test]
+fn test_move() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.turn_of(1);
+
+    battle.send(1, Message::Use(UseAnimal { animal_id: CAT }));
+    let received = battle.send(
+        1,
+        Message::Move(MoveAnimal {
+            position: TestBattle::position(1, 6, 3),
+        }),
+    );
+    let Some(Command::Moved(AnimalMoved {
+        position, squares, ..
+    })) = commands(&received, 1).first()
+    else {
+        panic!("{received:?}");
+    };
+    assert_eq!(position, &Some(Position { x: 6, y: 3 }));
+    assert_eq!(squares, &Some(3));
+
+    //Only one animal is used in a turn
+    let received = battle.send(1, Message::Use(UseAnimal { animal_id: MOUSE }));
+    assert!(has_error(&received, 1));
+}
+
+#[test]
+fn test_damage_and_death() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+
+    let mut damage = Vec::new();
+    let dead = loop {
+        battle.turn_of(1);
+        battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+        let received = battle.send(
+            1,
+            Message::Damage(DamageAnimal {
+                position: TestBattle::position(1, 0, 12),
+            }),
+        );
+        assert!(!has_error(&received, 1), "{received:?}");
+        for command in commands(&received, 2) {
+            match command {
+                Command::Damaged(AnimalDamaged {
+                    damaged_animal_id,
+                    damage: amount,
+                    ..
+                }) => {
+                    assert_eq!(*damaged_animal_id, CHICK);
+                    damage.push(*amount);
+                }
+                Command::Dead(AnimalDead { animal_id }) => {
+                    assert_eq!(*animal_id, CHICK);
+                }
+                _ => {}
+            }
+        }
+        if commands(&received, 2)
+            .iter()
+            .any(|f| matches!(f, Command::Dead(_)))
+        {
+            break damage;
+        }
+        assert!(damage.len() < 3, "{damage:?}");
+
+        //Only one hit in a turn
+        let received = battle.send(
+            1,
+            Message::Damage(DamageAnimal {
+                position: TestBattle::position(1, 0, 12),
+            }),
+        );
+        assert!(has_error(&received, 1));
+        battle.send(1, Message::End(EndTurn {}));
+    };
+    //The fox deals 32 damage to the chick, that has 80 health
+    assert_eq!(dead, vec![32, 32, 16]);
+
+    //Dead animals cannot be hit
+    battle.send(1, Message::End(EndTurn {}));
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+    let received = battle.send(
+        1,
+        Message::Damage(DamageAnimal {
+            position: TestBattle::position(1, 0, 12),
+        }),
+    );
+    assert!(has_error(&received, 1));
+    assert!(!battle.battle.is_finished());
+}
+
+#[test]
+fn test_ability_on_enemy() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+
+    let received = battle.cast(1, "Deep Bite", TestBattle::position(1, 1, 11));
+    assert!(has_error(&received, 1));
+
+    let received = battle.cast(1, "Deep Bite", TestBattle::position(1, 0, 12));
+    let used = AbilityUsed {
+        player_id: 1,
+        animal_id: FOX,
+        ability: "Deep Bite".to_string(),
+        position: Some(Position { x: 0, y: 12 }),
+        cooldown: 4,
+        //The fox has 75 and the bite costs 20
+        action_points: Some(55f32),
+    };
+    assert_eq!(ability_used(&received, 1), Some(&used));
+    assert_eq!(
+        ability_used(&received, 2),
+        Some(&AbilityUsed {
+            action_points: None,
+            ..used
+        })
+    );
+    //30% of the 80 health of the chick, reduced by its resistance
+    let damaged = Command::Damaged(AnimalDamaged {
+        player_id: 1,
+        damaged_animal_id: CHICK,
+        damager_animal_id: FOX,
+        damage: 22,
+        action_points: None,
+    });
+    for player_id in [1, 2] {
+        assert!(commands(&received, player_id).contains(&&damaged));
+    }
+
+    let received = battle.cast(1, "Deep Bite", TestBattle::position(1, 0, 12));
+    assert!(has_error(&received, 1));
+}
+
+#[test]
+fn test_ability_on_empty_square() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+
+    let received = battle.cast(1, "It's a trap", TestBattle::position(1, 0, 12));
+    assert!(has_error(&received, 1));
+
+    let received = battle.cast(1, "It's a trap", TestBattle::position(1, 1, 11));
+    let used = ability_used(&received, 1).unwrap();
+    assert_eq!(used.position, Some(Position { x: 1, y: 11 }));
+    assert_eq!(used.action_points, Some(50f32));
+    assert!(commands(&received, 1)
+        .iter()
+        .any(|f| matches!(f, Command::ObjectPlaced(_))));
+
+    //The opponent knows about the trap, but not where it is
+    let used = ability_used(&received, 2).unwrap();
+    assert_eq!(used.position, None);
+    assert!(!commands(&received, 2)
+        .iter()
+        .any(|f| matches!(f, Command::ObjectPlaced(_))));
+
+    //Until the chick steps on it
+    battle.send(1, Message::End(EndTurn {}));
+    battle.send(2, Message::Use(UseAnimal { animal_id: CHICK }));
+    let mut received = HashMap::new();
+    for (x, y) in [(1, 12), (1, 11)] {
+        received = battle.send(
+            2,
+            Message::Move(MoveAnimal {
+                position: TestBattle::position(2, x, y),
+            }),
+        );
+        assert!(!has_error(&received, 2), "{received:?}");
+    }
+    let damaged = Command::Damaged(AnimalDamaged {
+        player_id: 1,
+        damaged_animal_id: CHICK,
+        damager_animal_id: FOX,
+        damage: 35,
+        action_points: None,
+    });
+    for player_id in [1, 2] {
+        assert!(commands(&received, player_id).contains(&&damaged));
+    }
+    let removed = Command::ObjectRemoved(ObjectRemoved {
+        position: Some(Position { x: 1, y: 11 }),
+    });
+    assert!(commands(&received, 1).contains(&&removed));
+    assert!(!commands(&received, 2).contains(&&removed));
+}
+
+#[test]
+fn test_ability_without_target() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+
+    let received = battle.cast(1, "Sneak", None);
+    assert_eq!(
+        ability_used(&received, 1).unwrap().action_points,
+        Some(60f32)
+    );
+    let invisible = Command::EffectApplied(EffectApplied {
+        animal_id: FOX,
+        effect: EffectType::Invisible.into(),
+        turns: 1,
+        value: 0,
+    });
+    for player_id in [1, 2] {
+        assert!(commands(&received, player_id).contains(&&invisible));
+    }
+
+    //The opponent has nobody, who could see invisible animals
+    let received = battle.send(
+        1,
+        Message::Move(MoveAnimal {
+            position: TestBattle::position(1, 1, 11),
+        }),
+    );
+    assert!(matches!(commands(&received, 1)[..], [Command::Moved(_)]));
+    assert!(received[&2].is_empty());
+}
+
+#[test]
+fn test_nine_lives() {
+    let mut battle = TestBattle::new(0);
+    battle.face_off([[FOX, CHICK, MOUSE], [CAT, PIG, RABBIT]]);
+
+    //The fox deals 30 damage to the cat, that has 120 health
+    let mut revived = 0;
+    for hits in 1.. {
+        assert!(hits <= 5);
+        battle.turn_of(1);
+        battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+        let received = battle.send(
+            1,
+            Message::Damage(DamageAnimal {
+                position: TestBattle::position(1, 0, 12),
+            }),
+        );
+        assert!(!has_error(&received, 1), "{received:?}");
+        if commands(&received, 2).contains(&&Command::Dead(AnimalDead { animal_id: CAT })) {
+            break;
+        }
+        if let Some(used) = ability_used(&received, 2) {
+            assert_eq!(used.ability, "Nine Lives");
+            assert_eq!(used.cooldown, 10);
+            //10% of the health
+            assert!(
+                commands(&received, 2).contains(&&Command::Healed(AnimalHealed {
+                    animal_id: CAT,
+                    amount: 12,
+                }))
+            );
+            revived = hits;
+        }
+        battle.send(1, Message::End(EndTurn {}));
+    }
+    //The cat is dead after the next hit, Nine Lives is on cooldown
+    assert_eq!(revived, 4);
+}
+
+#[test]
+fn test_stun() {
+    let mut battle = TestBattle::new(0);
+    battle.face_off([[CAT, FOX, MOUSE], [CHICK, PIG, RABBIT]]);
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: CAT }));
+    let received = battle.cast(1, "Pounce", TestBattle::position(1, 0, 12));
+    assert!(!has_error(&received, 1), "{received:?}");
+    battle.send(1, Message::End(EndTurn {}));
+
+    //The chick misses the next turn of its player
+    let received = battle.send(2, Message::Use(UseAnimal { animal_id: CHICK }));
+    assert!(has_error(&received, 2));
+    let received = battle.send(2, Message::End(EndTurn {}));
+    assert!(
+        commands(&received, 2).contains(&&Command::EffectExpired(EffectExpired {
+            animal_id: CHICK,
+            effect: EffectType::Stun.into(),
+        }))
+    );
+
+    battle.send(1, Message::End(EndTurn {}));
+    let received = battle.send(2, Message::Use(UseAnimal { animal_id: CHICK }));
+    assert!(!has_error(&received, 2));
+}
+
+#[test]
+fn test_bleed() {
+    let mut battle = TestBattle::new(0);
+    battle.face_off([[CAT, CHICK, MOUSE], [FOX, PIG, RABBIT]]);
+    battle.turn_of(1);
+    battle.send(1, Message::Use(UseAnimal { animal_id: CAT }));
+    let received = battle.cast(1, "Scratch", TestBattle::position(1, 0, 12));
+    assert!(
+        commands(&received, 2).contains(&&Command::EffectApplied(EffectApplied {
+            animal_id: FOX,
+            effect: EffectType::Bleed.into(),
+            turns: 4,
+            value: 5,
+        }))
+    );
+
+    //The fox bleeds at the end of every turn of its player
+    let bleed = Command::Damaged(AnimalDamaged {
+        player_id: 1,
+        damaged_animal_id: FOX,
+        damager_animal_id: CAT,
+        damage: 5,
+        action_points: None,
+    });
+    let expired = Command::EffectExpired(EffectExpired {
+        animal_id: FOX,
+        effect: EffectType::Bleed.into(),
+    });
+    let end_turns = |battle: &mut TestBattle, turns: i32| {
+        for turn in 1..=turns {
+            let received = battle.send(1, Message::End(EndTurn {}));
+            assert!(!commands(&received, 2).contains(&&bleed));
+            let received = battle.send(2, Message::End(EndTurn {}));
+            let commands = commands(&received, 2);
+            assert_eq!(commands.iter().filter(|f| ***f == bleed).count(), 1);
+            assert_eq!(commands.contains(&&expired), turn == 4, "{commands:?}");
+        }
+    };
+    end_turns(&mut battle, 2);
+
+    //Scratching again refreshes the bleeding, it is not applied twice
+    battle.send(1, Message::Use(UseAnimal { animal_id: CAT }));
+    let received = battle.cast(1, "Scratch", TestBattle::position(1, 0, 12));
+    assert!(!has_error(&received, 1), "{received:?}");
+    assert!(!commands(&received, 2)
+        .iter()
+        .any(|f| matches!(f, Command::EffectApplied(_))));
+    end_turns(&mut battle, 4);
+}
+
+#[test]
+fn test_effect_stats() {
+    let mut battle = TestBattle::new(0);
+    battle.face_off([[CHICK, CAT, MOUSE], [PIG, FOX, RABBIT]]);
+    battle.turn_of(1);
+
+    //Extra steps and the shield last for two more turns, the damage boost for three
+    battle.send(1, Message::Use(UseAnimal { animal_id: CHICK }));
+    battle.cast(1, "Flutter", None);
+    battle.cast(1, "Cluck", None);
+    assert_eq!(battle.mobility(1, CHICK), 15);
+    //The pig has 22% resistance, the chick hits with 21 instead of 18
+    assert_eq!(hit(&mut battle, 1, 0, 12), 16);
+    battle.send(1, Message::End(EndTurn {}));
+    battle.send(2, Message::Use(UseAnimal { animal_id: PIG }));
+    //40% more resistance, than the 6% of the chick
+    assert_eq!(hit(&mut battle, 2, 0, 11), 13);
+
+    let expired = |received: &Received, effect: EffectType| {
+        commands(received, 1).contains(&&Command::EffectExpired(EffectExpired {
+            animal_id: CHICK,
+            effect: effect.into(),
+        }))
+    };
+    for turn in 1..=2 {
+        battle.send(2, Message::End(EndTurn {}));
+        let received = battle.send(1, Message::End(EndTurn {}));
+        assert_eq!(expired(&received, EffectType::ExtraSteps), turn == 2);
+        assert_eq!(expired(&received, EffectType::Shield), turn == 2);
+        assert!(!expired(&received, EffectType::DamageBoost));
+    }
+    assert_eq!(battle.mobility(1, CHICK), 10);
+    battle.send(2, Message::Use(UseAnimal { animal_id: PIG }));
+    assert_eq!(hit(&mut battle, 2, 0, 11), 23);
+
+    battle.send(2, Message::End(EndTurn {}));
+    let received = battle.send(1, Message::End(EndTurn {}));
+    assert!(expired(&received, EffectType::DamageBoost));
+    battle.send(2, Message::End(EndTurn {}));
+    battle.send(1, Message::Use(UseAnimal { animal_id: CHICK }));
+    assert_eq!(hit(&mut battle, 1, 0, 12), 14);
+}
+
+#[test]
+fn test_surrender() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    let received = battle.send(1, Message::Surrender(SurrenderBattle {}));
+    for player_id in [1, 2] {
+        assert!(has_state(&received, player_id, BattleState::Finished));
+    }
+    assert!(battle.battle.is_finished());
+    assert_eq!(battle.battle.winner(), Some(2));
+}
+
+//The test battle waits 60 seconds for disconnected players
+#[test]
+fn test_disconnect() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.run(Some(BattleMessage::Disconnected { player_id: 1 }));
+    battle.tick(59);
+    assert!(!battle.battle.is_finished());
+
+    let received = battle.tick(1);
+    assert!(has_state(&received, 2, BattleState::Finished));
+    assert_eq!(battle.battle.winner(), Some(2));
+}
+
+//The test battle waits 60 seconds for players to connect
+#[test]
+fn test_never_connected() {
+    let mut battle = TestBattle::new(0);
+    battle.run(Some(BattleMessage::Connected { player_id: 1 }));
+    battle.send(1, Message::Ready(Ready {}));
+    battle.tick(59);
+    assert!(!battle.battle.is_finished());
+
+    let received = battle.tick(1);
+    assert!(has_state(&received, 1, BattleState::Finished));
+    assert_eq!(battle.battle.winner(), Some(1));
+}
+
+#[test]
+fn test_reconnect() {
+    let mut battle = TestBattle::new(0);
+    battle.prepare();
+    battle.run(Some(BattleMessage::Disconnected { player_id: 1 }));
+    battle.tick(30);
+    battle.run(Some(BattleMessage::Connected { player_id: 1 }));
+
+    let received = battle.tick(60);
+    assert!(!has_state(&received, 2, BattleState::Finished));
+    assert!(!battle.battle.is_finished());
+}
+
+#[test]
+fn test_last_animal_dead() {
+    //One animal for every player
+    let rules = BattleRules {
+        team_size: 1,
+        ..BattleRules::load()
+    };
+    let mut battle = TestBattle::with_rules(0, rules);
+    battle.start();
+    battle.pick_animals([&[FOX], &[CHICK]]);
+    //Both send the row from their side, so the animals face each other
+    for (player_id, animal_id) in [(1, FOX), (2, CHICK)] {
+        battle.send(
+            player_id,
+            Message::Place(PlaceAnimals {
+                animals: vec![PlaceAnimal {
+                    animal_id,
+                    position: Some(Position { x: 0, y: 11 }),
+                }],
+            }),
+        );
+    }
+
+    //The fox needs three hits for the chick
+    for hits in 1..=3 {
+        assert!(!battle.battle.is_finished());
+        battle.turn_of(1);
+        battle.send(1, Message::Use(UseAnimal { animal_id: FOX }));
+        let received = battle.send(
+            1,
+            Message::Damage(DamageAnimal {
+                position: TestBattle::position(1, 0, 12),
+            }),
+        );
+        for player_id in [1, 2] {
+            assert_eq!(
+                has_state(&received, player_id, BattleState::Finished),
+                hits == 3
+            );
+        }
+        battle.send(1, Message::End(EndTurn {}));
+    }
+    assert!(battle.battle.is_finished());
+    assert_eq!(battle.battle.winner(), Some(1));
+}
+
+#[test]
+fn test_rules_and_map_from_files() -> Result<(), Box<dyn std::error::Error>> {
+    //One animal for every player
+    let rules = BattleRules {
+        team_size: 1,
+        ..BattleRules::load()
+    };
+    let path = std::env::temp_dir().join("test_rules_from_file.json");
+    std::fs::write(&path, serde_json::to_string(&rules)?)?;
+    let rules = BattleRules::from_file(path.to_str().unwrap())?;
+
+    //The map declares a smaller board and its own zones
+    let dir = std::env::temp_dir().join("test_rules_and_map_from_files");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::copy("data/animals.json", dir.join("animals.json"))?;
+    let maps = serde_json::json!({
+        "maps": [{
+            "map_name": "Small",
+            "width": 5,
+            "height": 10,
+            "player1_zone": { "start": 0, "end": 3 },
+            "player2_zone": { "start": 7, "end": 10 },
+            "objects": [
+                { "x": 1, "y": 2, "object_type": "solid" },
+                { "x": 2, "y": 2, "object_type": "can_walk_through" }
+            ]
+        }]
+    });
+    std::fs::write(dir.join("maps.json"), maps.to_string())?;
+    let content = ContentStore::from_dir(&dir, rules.clone())?;
+
+    let mut battle = TestBattle::with_content(0, rules, content);
+    battle.start();
+    let received = battle.pick_animals([&[FOX], &[CHICK]]);
+    assert!(has_state(&received, 1, BattleState::PlacementStage));
+
+    //Rows are flipped on the smaller board too
+    let received = battle.send(
+        1,
+        Message::Place(PlaceAnimals {
+            animals: vec![PlaceAnimal {
+                animal_id: FOX,
+                position: Some(Position { x: 0, y: 3 }),
+            }],
+        }),
+    );
+    assert!(has_error(&received, 1));
+    //Solid objects take their squares, walkable ones do not
+    let received = battle.send(
+        1,
+        Message::Place(PlaceAnimals {
+            animals: vec![PlaceAnimal {
+                animal_id: FOX,
+                position: Some(Position { x: 1, y: 2 }),
+            }],
+        }),
+    );
+    assert!(has_error(&received, 1));
+    battle.send(
+        1,
+        Message::Place(PlaceAnimals {
+            animals: vec![PlaceAnimal {
+                animal_id: FOX,
+                position: Some(Position { x: 2, y: 2 }),
+            }],
+        }),
+    );
+    let received = battle.send(
+        2,
+        Message::Place(PlaceAnimals {
+            animals: vec![PlaceAnimal {
+                animal_id: CHICK,
+                position: Some(Position { x: 0, y: 0 }),
+            }],
+        }),
+    );
+    assert!(
+        has_state(&received, 1, BattleState::GameStage),
+        "{received:?}"
+    );
+    let placed = commands(&received, 1)
+        .into_iter()
+        .find_map(|f| match f {
+            Command::Placed(placed) => Some(placed.animals.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(placed
+        .iter()
+        .any(|f| f.animal_id == CHICK && f.position == Some(Position { x: 0, y: 9 })));
+    Ok(())
+}